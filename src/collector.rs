@@ -0,0 +1,381 @@
+use std::sync::{Arc, RwLock};
+use std::time;
+
+use prometheus::core::{Collector as _, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+
+use crate::walk::{get_service_measurements, get_system_measurements};
+use crate::{kubelet, kubernetes, singleflight};
+use crate::{Measurements, Options};
+
+/// Collector of pressure for every cgroup and the whole system, along with
+/// metrics about the collection itself.
+pub struct Collector {
+    options: RwLock<Arc<Options>>,
+    metrics: Metrics,
+    collection: Collection,
+    descs: Vec<Desc>,
+}
+
+enum Collection {
+    /// Concurrent scrapes share a single walk of the cgroup tree
+    OnDemand(singleflight::Group<Measurements>),
+    /// Scrapes are served from a snapshot refreshed in the background
+    Background(RwLock<Arc<Measurements>>),
+}
+
+impl Collector {
+    /// Creates a collector walking the cgroup tree on every scrape.
+    pub fn new(options: Options) -> Self {
+        Self::with_collection(options, Collection::OnDemand(singleflight::Group::new()))
+    }
+
+    /// Creates a collector serving scrapes from a snapshot of measurements,
+    /// which is only updated by [`Collector::refresh`]. Families are still
+    /// built from the snapshot on every scrape.
+    pub fn background(options: Options) -> Self {
+        Self::with_collection(
+            options,
+            Collection::Background(RwLock::new(Arc::new(Measurements::new()))),
+        )
+    }
+
+    fn with_collection(options: Options, collection: Collection) -> Self {
+        let metrics = Metrics::new();
+        let labels = label_names(&options);
+
+        let mut descs: Vec<Desc> = FAMILIES
+            .iter()
+            .map(|family| {
+                Desc::new(
+                    family.name.to_string(),
+                    family.help.to_string(),
+                    labels.iter().map(|label| label.to_string()).collect(),
+                    Default::default(),
+                )
+                .unwrap()
+            })
+            .chain(metrics.desc().into_iter().cloned())
+            .chain(
+                options
+                    .cgroups
+                    .kubelet
+                    .iter()
+                    .flat_map(|resolver| resolver.desc().into_iter().cloned()),
+            )
+            .collect();
+
+        descs.sort_by(|a, b| a.fq_name.cmp(&b.fq_name));
+
+        Collector {
+            options: RwLock::new(Arc::new(options)),
+            metrics,
+            collection,
+            descs,
+        }
+    }
+
+    pub fn options(&self) -> Arc<Options> {
+        self.options.read().unwrap().clone()
+    }
+
+    /// Replaces options for the following collections.
+    ///
+    /// Descriptions of metrics keep labels of the options the collector
+    /// was created with, even if kubernetes mode or kubelet is toggled.
+    pub fn set_options(&self, options: Options) {
+        *self.options.write().unwrap() = Arc::new(options);
+    }
+
+    /// Returns pressure from the latest snapshot for background collectors,
+    /// or from a fresh walk otherwise.
+    pub fn measurements(&self) -> Arc<Measurements> {
+        match &self.collection {
+            Collection::OnDemand(group) => group.run(|| self.collect()),
+            Collection::Background(snapshot) => snapshot.read().unwrap().clone(),
+        }
+    }
+
+    /// Updates the snapshot of background collectors.
+    pub fn refresh(&self) {
+        if let Collection::Background(snapshot) = &self.collection {
+            let measurements = Arc::new(self.collect());
+            *snapshot.write().unwrap() = measurements;
+        }
+    }
+
+    /// Returns metric families for the given measurements, along with
+    /// metrics about the collection, sorted by name.
+    pub fn families(&self, measurements: &Measurements) -> Vec<MetricFamily> {
+        let options = self.options();
+        let labels = &label_names(&options);
+
+        let mut samples: Vec<_> = measurements
+            .iter()
+            .flat_map(|(id, measurements)| {
+                measurements
+                    .controllers
+                    .iter()
+                    .flat_map(move |(controller, stats)| {
+                        let kinds = [("some", &stats.some), ("full", &stats.full)];
+
+                        IntoIterator::into_iter(kinds).filter_map(move |(kind, psi)| {
+                            let values: Vec<&str> = labels
+                                .iter()
+                                .map(|label| match *label {
+                                    "controller" => controller.as_str(),
+                                    "id" => id.as_str(),
+                                    "kind" => kind,
+                                    label => measurements
+                                        .kubernetes
+                                        .as_ref()
+                                        .map(|cgroup| cgroup.label(label))
+                                        .unwrap_or_default(),
+                                })
+                                .collect();
+
+                            Some((values, psi.as_ref()?))
+                        })
+                    })
+            })
+            .collect();
+
+        // Label values in the order of label names, as the registry would
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut families: Vec<_> = FAMILIES
+            .iter()
+            .filter(|family| family.kind == MetricType::COUNTER || options.report_avg)
+            .map(|family| {
+                let metrics = samples
+                    .iter()
+                    .map(|(values, psi)| (values, (family.value)(psi)))
+                    .filter(|(_, value)| options.report_zeros || *value > 0.0)
+                    .map(|(values, value)| family.metric(labels, values, value))
+                    .collect();
+
+                family.encode(metrics)
+            })
+            .chain(self.metrics.collect())
+            .chain(
+                options
+                    .cgroups
+                    .kubelet
+                    .iter()
+                    .flat_map(|resolver| resolver.collect()),
+            )
+            .filter(|family| !family.get_metric().is_empty())
+            .collect();
+
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        families
+    }
+
+    fn collect(&self) -> Measurements {
+        let start = time::Instant::now();
+
+        let options = self.options();
+
+        let mut measurements = get_service_measurements(&options.cgroups, &self.metrics);
+
+        if let Some(resolver) = &options.cgroups.kubelet {
+            for (id, measurements) in measurements.iter_mut() {
+                if let Some(cgroup) = &mut measurements.kubernetes {
+                    if let Err(e) = resolver.resolve(cgroup) {
+                        log::warn!(id = id.as_str(), err = e.as_str(); "Error resolving pod names");
+                    }
+                }
+            }
+        }
+
+        if let Some(dir) = &options.system_pressure {
+            measurements.extend(get_system_measurements(dir, &self.metrics));
+        }
+
+        let duration = start.elapsed().as_secs_f64();
+
+        self.metrics.scrape_duration.set(duration);
+
+        log::debug!(
+            duration_seconds = duration,
+            cgroups = self.metrics.cgroups_scanned.get();
+            "Collected pressure"
+        );
+
+        if let Ok(now) = time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            self.metrics.last_collection.set(now.as_secs_f64());
+        }
+
+        measurements
+    }
+}
+
+impl prometheus::core::Collector for Collector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.families(&self.measurements())
+    }
+}
+
+/// Names of labels of pressure metrics, sorted.
+const LABELS: [&str; 3] = ["controller", "id", "kind"];
+
+/// Returns names of labels of pressure metrics for the options, sorted.
+fn label_names(options: &Options) -> Vec<&'static str> {
+    let mut labels = LABELS.to_vec();
+
+    if options.cgroups.kubernetes {
+        labels.extend_from_slice(&kubernetes::LABELS);
+
+        if options.cgroups.kubelet.is_some() {
+            labels.extend_from_slice(&kubelet::LABELS);
+        }
+
+        labels.sort_unstable();
+    }
+
+    labels
+}
+
+/// Pressure metric family, with a value for every `some` and `full` line.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricType,
+    value: fn(&psi::Psi) -> f64,
+}
+
+const FAMILIES: [Family; 4] = [
+    Family {
+        name: "pressure_total_seconds",
+        help: "Total time spent under pressure",
+        kind: MetricType::COUNTER,
+        value: |psi| psi.total.as_nanos() as f64 / 1e9,
+    },
+    Family {
+        name: "pressure_avg_10s_ratio",
+        help: "Ratio of time spent under pressure in the last 10s at time of measurement",
+        kind: MetricType::GAUGE,
+        value: |psi| f64::from(psi.avg10) / 100.0,
+    },
+    Family {
+        name: "pressure_avg_60s_ratio",
+        help: "Ratio of time spent under pressure in the last 60s at time of measurement",
+        kind: MetricType::GAUGE,
+        value: |psi| f64::from(psi.avg60) / 100.0,
+    },
+    Family {
+        name: "pressure_avg_300s_ratio",
+        help: "Ratio of time spent under pressure in the last 300s at time of measurement",
+        kind: MetricType::GAUGE,
+        value: |psi| f64::from(psi.avg300) / 100.0,
+    },
+];
+
+impl Family {
+    fn metric(&self, labels: &[&str], values: &[&str], value: f64) -> Metric {
+        let mut metric = Metric::new();
+
+        metric.set_label(
+            labels
+                .iter()
+                .zip(values)
+                .map(|(name, value)| {
+                    let mut label = LabelPair::new();
+                    label.set_name(name.to_string());
+                    label.set_value(value.to_string());
+                    label
+                })
+                .collect(),
+        );
+
+        if self.kind == MetricType::COUNTER {
+            let mut counter = Counter::new();
+            counter.set_value(value);
+            metric.set_counter(counter);
+        } else {
+            let mut gauge = Gauge::new();
+            gauge.set_value(value);
+            metric.set_gauge(gauge);
+        }
+
+        metric
+    }
+
+    fn encode(&self, metrics: Vec<Metric>) -> MetricFamily {
+        let mut family = MetricFamily::new();
+
+        family.set_name(self.name.to_string());
+        family.set_help(self.help.to_string());
+        family.set_field_type(self.kind);
+        family.set_metric(metrics.into());
+
+        family
+    }
+}
+
+/// Metrics about the collection itself.
+pub(crate) struct Metrics {
+    pub(crate) scrape_duration: prometheus::Gauge,
+    pub(crate) last_collection: prometheus::Gauge,
+    pub(crate) cgroups_scanned: prometheus::IntGauge,
+    pub(crate) files_read: prometheus::IntCounter,
+    pub(crate) read_errors: prometheus::IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics {
+            scrape_duration: prometheus::Gauge::new(
+                "psi_exporter_scrape_duration_seconds",
+                "Time spent collecting pressure the last time",
+            )
+            .unwrap(),
+            last_collection: prometheus::Gauge::new(
+                "psi_exporter_last_collection_timestamp_seconds",
+                "Unix time when pressure was last collected",
+            )
+            .unwrap(),
+            cgroups_scanned: prometheus::IntGauge::new(
+                "psi_exporter_cgroups_scanned",
+                "Number of cgroups scanned during the last collection",
+            )
+            .unwrap(),
+            files_read: prometheus::IntCounter::new(
+                "psi_exporter_pressure_files_read_total",
+                "Number of pressure files successfully read",
+            )
+            .unwrap(),
+            read_errors: prometheus::IntCounterVec::new(
+                prometheus::opts!(
+                    "psi_exporter_read_errors_total",
+                    "Number of pressure files that could not be read, by reason"
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+        }
+    }
+
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.scrape_duration.desc();
+        descs.extend(self.last_collection.desc());
+        descs.extend(self.cgroups_scanned.desc());
+        descs.extend(self.files_read.desc());
+        descs.extend(self.read_errors.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.scrape_duration.collect();
+        families.extend(self.last_collection.collect());
+        families.extend(self.cgroups_scanned.collect());
+        families.extend(self.files_read.collect());
+        families.extend(self.read_errors.collect());
+        families
+    }
+}
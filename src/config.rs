@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::listener;
+
+/// Configuration file with the same settings as command line flags,
+/// which take precedence when given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub web: Web,
+    #[serde(default)]
+    pub cgroup: Cgroup,
+    #[serde(default)]
+    pub kubelet: Kubelet,
+    #[serde(default)]
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Web {
+    #[serde(default)]
+    pub listen_addresses: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cgroup {
+    #[serde(default)]
+    pub roots: Vec<PathBuf>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    #[serde(default)]
+    pub relabel: Vec<Relabel>,
+    #[serde(default)]
+    pub kubernetes: bool,
+}
+
+/// Rewrites `id` label of cgroups matching the regex, the first matching
+/// rule wins.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Relabel {
+    pub regex: String,
+    pub replacement: String,
+}
+
+/// Resolution of pod names, only read at startup.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Kubelet {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    #[serde(default)]
+    pub refresh_interval: Option<String>,
+    #[serde(default)]
+    pub timeout: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    #[serde(default)]
+    pub disable_avg: bool,
+    #[serde(default)]
+    pub silence_zeros: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+        let config: Config = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("error parsing {}: {}", path.display(), e))?;
+
+        for addr in &config.web.listen_addresses {
+            listener::Listener::validate(addr)
+                .map_err(|e| format!("invalid listen address {}: {}", addr, e))?;
+        }
+
+        let regexes = config
+            .cgroup
+            .include
+            .iter()
+            .chain(&config.cgroup.exclude)
+            .chain(config.cgroup.relabel.iter().map(|r| &r.regex));
+
+        for regex in regexes {
+            regex::Regex::new(regex).map_err(|e| format!("invalid regex {}: {}", regex, e))?;
+        }
+
+        if let Some(interval) = &config.kubelet.refresh_interval {
+            humantime::parse_duration(interval)
+                .map_err(|e| format!("invalid kubelet refresh interval {}: {}", interval, e))?;
+        }
+
+        if let Some(timeout) = &config.kubelet.timeout {
+            match humantime::parse_duration(timeout) {
+                Ok(duration) if duration.as_nanos() == 0 => {
+                    return Err(format!(
+                        "invalid kubelet timeout {}: must be greater than zero",
+                        timeout
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => return Err(format!("invalid kubelet timeout {}: {}", timeout, e)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!(
+            "psi_exporter-config-{}-{}.yml",
+            name,
+            std::process::id()
+        ));
+
+        fs::write(&path, contents).unwrap();
+        let config = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn load_full() {
+        let config = load(
+            "full",
+            "web:\n  listen_addresses: ['127.0.0.1:9100', 'unix:/run/psi.sock']\n\
+             cgroup:\n  roots: [/sys/fs/cgroup]\n  include: ['/system\\.slice/.*']\n  \
+             exclude: ['/user\\.slice']\n  max_depth: 2\n  \
+             relabel:\n    - regex: '/system\\.slice/(.*)\\.service'\n      replacement: 'service/$1'\n\
+             metrics:\n  disable_avg: true\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.web.listen_addresses,
+            vec!["127.0.0.1:9100", "unix:/run/psi.sock"]
+        );
+        assert_eq!(config.cgroup.roots, vec![PathBuf::from("/sys/fs/cgroup")]);
+        assert_eq!(config.cgroup.include, vec!["/system\\.slice/.*"]);
+        assert_eq!(config.cgroup.exclude, vec!["/user\\.slice"]);
+        assert_eq!(config.cgroup.max_depth, Some(2));
+        assert_eq!(config.cgroup.relabel.len(), 1);
+        assert_eq!(config.cgroup.relabel[0].replacement, "service/$1");
+        assert!(config.metrics.disable_avg);
+        assert!(!config.metrics.silence_zeros);
+    }
+
+    #[test]
+    fn load_empty() {
+        let config = load("empty", "{}\n").unwrap();
+
+        assert!(config.web.listen_addresses.is_empty());
+        assert!(config.cgroup.roots.is_empty());
+        assert_eq!(config.cgroup.max_depth, None);
+    }
+
+    #[test]
+    fn load_invalid() {
+        assert!(load("unknown", "cgroup:\n  max_deph: 2\n").is_err());
+        assert!(load("address", "web:\n  listen_addresses: ['localhost']\n").is_err());
+        assert!(load("include", "cgroup:\n  include: ['(']\n").is_err());
+        assert!(load(
+            "relabel",
+            "cgroup:\n  relabel:\n    - regex: '['\n      replacement: ''\n"
+        )
+        .is_err());
+        assert!(load("timeout", "kubelet:\n  timeout: 0s\n").is_err());
+
+        let missing = Config::load(Path::new("/nonexistent/psi_exporter.yml"));
+        assert!(missing
+            .unwrap_err()
+            .contains("/nonexistent/psi_exporter.yml"));
+    }
+}
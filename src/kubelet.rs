@@ -0,0 +1,379 @@
+//! Resolution of pod uids and container ids into names through the
+//! `/pods` endpoint of the local kubelet.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use prometheus::core::{Collector as _, Desc};
+use prometheus::proto::MetricFamily;
+use serde::Deserialize;
+
+use crate::kubernetes;
+
+/// Labels added to pressure metrics when pods are resolved, sorted.
+pub const LABELS: [&str; 3] = ["container", "namespace", "pod"];
+
+/// Location of kubelet and the way to talk to it.
+pub struct Endpoint {
+    /// Base url, like `https://127.0.0.1:10250` or `http://127.0.0.1:10255`
+    pub url: String,
+    /// File with a bearer token, re-read on every request to pick up
+    /// rotated tokens
+    pub token_file: Option<PathBuf>,
+    /// Certificates to verify kubelet with instead of the system roots
+    pub ca_file: Option<PathBuf>,
+    /// Whether certificate of kubelet is not verified at all, as its
+    /// self-signed serving certificate can't be
+    pub insecure_skip_verify: bool,
+    pub timeout: time::Duration,
+    /// Minimum time between requests, pods unknown in the meantime are
+    /// left without names
+    pub refresh_interval: time::Duration,
+}
+
+/// Cache of names of pods and their containers, refreshed from kubelet
+/// when an unknown pod or container is seen.
+///
+/// Containers kubelet doesn't report, like pod sandboxes, are remembered
+/// until their pod changes, so that these don't cause requests.
+pub struct Resolver {
+    url: String,
+    token_file: Option<PathBuf>,
+    refresh_interval: time::Duration,
+    agent: ureq::Agent,
+    cache: Mutex<Cache>,
+    requests: prometheus::IntCounterVec,
+}
+
+#[derive(Default)]
+struct Cache {
+    pods: HashMap<String, Pod>,
+    fetched: Option<time::Instant>,
+}
+
+struct Pod {
+    namespace: String,
+    name: String,
+    /// Names of containers by id, without the runtime prefix
+    containers: HashMap<String, String>,
+    /// Ids of containers seen in cgroups but missing from kubelet
+    missing: HashSet<String>,
+}
+
+impl Resolver {
+    pub fn new(endpoint: Endpoint) -> Result<Self, String> {
+        if !endpoint.url.starts_with("http://") && !endpoint.url.starts_with("https://") {
+            return Err(format!("unsupported kubelet url {}", endpoint.url));
+        }
+
+        let mut agent = ureq::AgentBuilder::new().timeout(endpoint.timeout);
+
+        if let Some(tls) = tls_config(&endpoint)? {
+            agent = agent.tls_config(tls);
+        }
+
+        Ok(Resolver {
+            url: format!("{}/pods", endpoint.url.trim_end_matches('/')),
+            token_file: endpoint.token_file,
+            refresh_interval: endpoint.refresh_interval,
+            agent: agent.build(),
+            cache: Mutex::new(Cache::default()),
+            requests: prometheus::IntCounterVec::new(
+                prometheus::opts!(
+                    "psi_exporter_kubelet_requests_total",
+                    "Number of requests to kubelet for names of pods, by result"
+                ),
+                &["result"],
+            )
+            .unwrap(),
+        })
+    }
+
+    /// Returns url of the `/pods` endpoint.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Fills names of the pod and container of the cgroup, asking kubelet
+    /// if either is not known yet.
+    pub fn resolve(&self, cgroup: &mut kubernetes::Cgroup) -> Result<(), String> {
+        if cgroup.pod_uid.is_empty() {
+            return Ok(());
+        }
+
+        // Scrapes resolving other cgroups meanwhile are not held up
+        let fetched = if self.needs_fetch(cgroup) {
+            let fetched = self.fetch();
+
+            let label = if fetched.is_ok() { "success" } else { "error" };
+            self.requests.with_label_values(&[label]).inc();
+
+            Some(fetched)
+        } else {
+            None
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        let mut result = Ok(());
+
+        match fetched {
+            Some(Ok(pods)) => {
+                cache.update(pods);
+
+                if let Some(pod) = cache.pods.get_mut(&cgroup.pod_uid) {
+                    if !cgroup.container_id.is_empty()
+                        && !pod.containers.contains_key(&cgroup.container_id)
+                    {
+                        pod.missing.insert(cgroup.container_id.clone());
+                    }
+                }
+            }
+            Some(Err(e)) => result = Err(e),
+            None => {}
+        }
+
+        if let Some(pod) = cache.pods.get(&cgroup.pod_uid) {
+            cgroup.namespace = pod.namespace.clone();
+            cgroup.pod = pod.name.clone();
+
+            if let Some(name) = pod.containers.get(&cgroup.container_id) {
+                cgroup.container = name.clone();
+            }
+        }
+
+        result
+    }
+
+    /// Returns whether kubelet should be asked about the cgroup, claiming
+    /// the request so that it is only made once per refresh interval.
+    fn needs_fetch(&self, cgroup: &kubernetes::Cgroup) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+
+        let known = cache
+            .pods
+            .get(&cgroup.pod_uid)
+            .map(|pod| {
+                cgroup.container_id.is_empty()
+                    || pod.containers.contains_key(&cgroup.container_id)
+                    || pod.missing.contains(&cgroup.container_id)
+            })
+            .unwrap_or(false);
+
+        let stale = cache
+            .fetched
+            .map(|fetched| fetched.elapsed() >= self.refresh_interval)
+            .unwrap_or(true);
+
+        if known || !stale {
+            return false;
+        }
+
+        cache.fetched = Some(time::Instant::now());
+
+        true
+    }
+
+    fn fetch(&self) -> Result<HashMap<String, Pod>, String> {
+        let mut request = self.agent.get(&self.url);
+
+        if let Some(path) = &self.token_file {
+            let token = fs::read_to_string(path)
+                .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+            request = request.set("Authorization", &format!("Bearer {}", token.trim()));
+        }
+
+        let response = request.call().map_err(|e| e.to_string())?;
+
+        let list: PodList = serde_json::from_reader(response.into_reader())
+            .map_err(|e| format!("error parsing pods: {}", e))?;
+
+        Ok(list
+            .items
+            .into_iter()
+            .map(|item| {
+                let containers = item
+                    .status
+                    .container_statuses
+                    .into_iter()
+                    .chain(item.status.init_container_statuses)
+                    .chain(item.status.ephemeral_container_statuses)
+                    .filter_map(|status| {
+                        // Ids are prefixed with runtime, like containerd://<id>
+                        let id = status.container_id?.rsplit("://").next()?.to_string();
+                        Some((id, status.name))
+                    })
+                    .collect();
+
+                let pod = Pod {
+                    namespace: item.metadata.namespace,
+                    name: item.metadata.name,
+                    containers,
+                    missing: HashSet::new(),
+                };
+
+                (item.metadata.uid, pod)
+            })
+            .collect())
+    }
+
+    pub(crate) fn desc(&self) -> Vec<&Desc> {
+        self.requests.desc()
+    }
+
+    pub(crate) fn collect(&self) -> Vec<MetricFamily> {
+        self.requests.collect()
+    }
+}
+
+impl Cache {
+    /// Replaces pods, keeping missing containers of pods that didn't change.
+    fn update(&mut self, mut pods: HashMap<String, Pod>) {
+        for (uid, pod) in &mut pods {
+            if let Some(cached) = self.pods.remove(uid) {
+                if cached.containers == pod.containers {
+                    pod.missing = cached.missing;
+                }
+            }
+        }
+
+        self.pods = pods;
+    }
+}
+
+fn tls_config(endpoint: &Endpoint) -> Result<Option<Arc<rustls::ClientConfig>>, String> {
+    let builder = rustls::ClientConfig::builder();
+
+    let config = if endpoint.insecure_skip_verify {
+        let provider = rustls::crypto::ring::default_provider();
+
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipVerify(provider)))
+            .with_no_client_auth()
+    } else if let Some(ca_file) = &endpoint.ca_file {
+        let mut roots = rustls::RootCertStore::empty();
+
+        for cert in load_certs(ca_file)? {
+            roots
+                .add(cert)
+                .map_err(|e| format!("invalid certificate in {}: {}", ca_file.display(), e))?;
+        }
+
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, String> {
+    let file =
+        fs::File::open(path).map_err(|e| format!("error opening {}: {}", path.display(), e))?;
+
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+
+    Ok(certs)
+}
+
+/// Verifier accepting any certificate, signatures of the handshake are
+/// still checked.
+#[derive(Debug)]
+struct SkipVerify(rustls::crypto::CryptoProvider);
+
+impl rustls::client::danger::ServerCertVerifier for SkipVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer,
+        _intermediates: &[rustls::pki_types::CertificateDer],
+        _server_name: &rustls::pki_types::ServerName,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Parts of `PodList` returned by kubelet that names come from.
+#[derive(Deserialize)]
+struct PodList {
+    #[serde(default)]
+    items: Vec<PodItem>,
+}
+
+#[derive(Deserialize)]
+struct PodItem {
+    metadata: Metadata,
+    #[serde(default)]
+    status: Status,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    name: String,
+    namespace: String,
+    uid: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+    #[serde(default)]
+    container_statuses: Vec<ContainerStatus>,
+    #[serde(default)]
+    init_container_statuses: Vec<ContainerStatus>,
+    #[serde(default)]
+    ephemeral_container_statuses: Vec<ContainerStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerStatus {
+    name: String,
+    /// Missing until the container is created
+    #[serde(rename = "containerID", default)]
+    container_id: Option<String>,
+}
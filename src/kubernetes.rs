@@ -0,0 +1,105 @@
+//! Parsing of cgroups created by kubelet for pods and their containers.
+//!
+//! Both cgroup drivers of kubelet are supported:
+//!
+//! * `systemd`: `/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod<uid>.slice/cri-containerd-<id>.scope`
+//! * `cgroupfs`: `/kubepods/burstable/pod<uid>/<id>`
+//!
+//! Pods with `Guaranteed` QoS class live right under the top level cgroup.
+
+/// Labels added to pressure metrics in kubernetes mode, sorted.
+pub const LABELS: [&str; 3] = ["container_id", "pod_uid", "qos_class"];
+
+const QOS_CLASSES: [&str; 2] = ["burstable", "besteffort"];
+const GUARANTEED: &str = "guaranteed";
+
+/// Prefixes container runtimes put in front of container ids, others like
+/// `crio-conmon-<id>` belong to helper processes of the container.
+const RUNTIME_PREFIXES: [&str; 3] = ["cri-containerd-", "crio-", "docker-"];
+
+/// Pod and container of a cgroup, fields are empty when the cgroup is
+/// higher up in the hierarchy.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Cgroup {
+    pub pod_uid: String,
+    pub qos_class: String,
+    pub container_id: String,
+    /// Names are only known once resolved through kubelet
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+}
+
+impl Cgroup {
+    /// Returns value of one of `LABELS` or `kubelet::LABELS`.
+    pub fn label(&self, name: &str) -> &str {
+        match name {
+            "container_id" => &self.container_id,
+            "pod_uid" => &self.pod_uid,
+            "qos_class" => &self.qos_class,
+            "namespace" => &self.namespace,
+            "pod" => &self.pod,
+            "container" => &self.container,
+            _ => "",
+        }
+    }
+}
+
+/// Parses cgroup id, returns `None` for cgroups outside of kubepods.
+pub fn parse(id: &str) -> Option<Cgroup> {
+    let mut segments = id
+        .split('/')
+        .skip_while(|segment| *segment != "kubepods" && !segment.ends_with("kubepods.slice"));
+
+    let systemd = segments.next()?.ends_with(".slice");
+
+    let mut cgroup = Cgroup::default();
+
+    for segment in segments {
+        let (name, scope) = if !systemd {
+            (segment, false)
+        } else if let Some(slice) = segment.strip_suffix(".slice") {
+            // Every slice repeats names of its parents, separated by dashes
+            (slice.rsplit('-').next().unwrap_or_default(), false)
+        } else if let Some(scope) = segment.strip_suffix(".scope") {
+            (scope, true)
+        } else {
+            break;
+        };
+
+        if !cgroup.pod_uid.is_empty() {
+            if systemd && !scope {
+                break;
+            }
+
+            // Runtime prefix is required for scopes, cgroupfs driver might
+            // use bare ids
+            let id = RUNTIME_PREFIXES
+                .iter()
+                .find_map(|prefix| name.strip_prefix(prefix))
+                .or(if scope { None } else { Some(name) });
+
+            if let Some(id) = id.filter(|id| !id.contains('-')) {
+                cgroup.container_id = id.to_string();
+            }
+
+            break;
+        }
+
+        if let Some(uid) = name.strip_prefix("pod") {
+            // Dashes in slice names are hierarchy separators, systemd
+            // driver replaces those in uids with underscores
+            cgroup.pod_uid = uid.replace('_', "-");
+        } else if QOS_CLASSES.contains(&name) && cgroup.qos_class.is_empty() {
+            cgroup.qos_class = name.to_string();
+        } else {
+            break;
+        }
+    }
+
+    if !cgroup.pod_uid.is_empty() && cgroup.qos_class.is_empty() {
+        cgroup.qos_class = GUARANTEED.to_string();
+    }
+
+    Some(cgroup)
+}
@@ -0,0 +1,133 @@
+//! Collection of [Pressure Stall Information] (PSI) from cgroup2 and
+//! `/proc/pressure` as Prometheus metrics.
+//!
+//! [`Collector`] walks the cgroup tree and implements
+//! `prometheus::core::Collector`, so it can be registered in any registry:
+//!
+//! ```
+//! let collector = psi_exporter::Collector::new(psi_exporter::Options::default());
+//!
+//! let registry = prometheus::Registry::new();
+//! registry.register(Box::new(collector)).unwrap();
+//! ```
+//!
+//! [Pressure Stall Information]: https://www.kernel.org/doc/html/latest/accounting/psi.html
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time;
+
+mod collector;
+pub mod kubelet;
+pub mod kubernetes;
+mod singleflight;
+mod walk;
+
+pub use collector::Collector;
+pub use walk::detect_cgroup_root;
+
+/// Pressure of every cgroup by `id`, system-wide pressure has `id` of
+/// [`SYSTEM_ID`].
+pub type Measurements = HashMap<String, PsiMeasurements>;
+
+/// Identifier of system-wide pressure from `/proc/pressure`.
+pub const SYSTEM_ID: &str = "system";
+
+/// Directory with system-wide pressure files.
+pub const SYSTEM_PRESSURE_DIR: &str = "/proc/pressure";
+
+/// Pressure of a single controller, like `cpu` or `io`.
+#[derive(Debug, Default)]
+pub struct PsiStats {
+    pub some: Option<psi::Psi>,
+    pub full: Option<psi::Psi>,
+    /// Time since when pressure has been accounted
+    pub created: Option<time::SystemTime>,
+}
+
+/// Pressure of a single cgroup by controller.
+#[derive(Debug, Default)]
+pub struct PsiMeasurements {
+    pub controllers: HashMap<String, PsiStats>,
+    /// Pod and container of the cgroup, only parsed in kubernetes mode
+    pub kubernetes: Option<kubernetes::Cgroup>,
+}
+
+/// Settings of the collector, which can be replaced while it is running.
+pub struct Options {
+    pub cgroups: CgroupOptions,
+    /// Directory with system-wide pressure, not collected if `None`
+    pub system_pressure: Option<PathBuf>,
+    /// Whether `pressure_avg_*` ratios are reported
+    pub report_avg: bool,
+    /// Whether series with zero values are reported
+    pub report_zeros: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cgroups: CgroupOptions {
+                roots: vec![detect_cgroup_root()],
+                include: None,
+                exclude: None,
+                max_depth: None,
+                relabel: vec![],
+                kubernetes: false,
+                kubelet: None,
+            },
+            system_pressure: Some(PathBuf::from(SYSTEM_PRESSURE_DIR)),
+            report_avg: true,
+            report_zeros: true,
+        }
+    }
+}
+
+/// Selection of cgroups to export.
+///
+/// Regular expressions are matched against cgroup ids as is, anchor them
+/// to match whole ids.
+pub struct CgroupOptions {
+    /// Roots of cgroup2 hierarchies to walk, ids are prefixed with the root
+    /// when there are several
+    pub roots: Vec<PathBuf>,
+    /// Only cgroups matching are exported
+    pub include: Option<regex::RegexSet>,
+    /// Cgroups matching are skipped along with their children
+    pub exclude: Option<regex::RegexSet>,
+    /// Cgroups nested deeper are skipped, with 0 being the root cgroup
+    pub max_depth: Option<usize>,
+    /// Rules rewriting `id` label, the first matching rule wins
+    pub relabel: Vec<(regex::Regex, String)>,
+    /// Whether kubepods cgroups are parsed into pod and container labels,
+    /// which also exports container scopes
+    pub kubernetes: bool,
+    /// Resolver of pod and container names in kubernetes mode, shared
+    /// between options to keep its cache
+    pub kubelet: Option<Arc<kubelet::Resolver>>,
+}
+
+impl CgroupOptions {
+    /// Returns `id` label for the cgroup after applying relabel rules.
+    fn relabel(&self, id: String) -> String {
+        match self.relabel.iter().find(|(regex, _)| regex.is_match(&id)) {
+            Some((regex, replacement)) => regex.replace(&id, replacement.as_str()).into_owned(),
+            None => id,
+        }
+    }
+
+    fn is_included(&self, id: &str) -> bool {
+        self.include
+            .as_ref()
+            .map(|r| r.is_match(id))
+            .unwrap_or(true)
+    }
+
+    fn is_excluded(&self, id: &str) -> bool {
+        self.exclude
+            .as_ref()
+            .map(|r| r.is_match(id))
+            .unwrap_or(false)
+    }
+}
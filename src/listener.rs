@@ -0,0 +1,163 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::net::{self, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use crate::tls;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// HTTP server accepting connections from a listener.
+pub struct Server {
+    pub http: tiny_http::Server,
+    /// Listening socket, owned by the thread accepting connections
+    fd: RawFd,
+    /// Removed after the server, which removes its socket on drop
+    _backend: Option<tls::Backend>,
+}
+
+impl Server {
+    /// Stops accepting connections, so that new ones are refused. Requests
+    /// accepted by now are still returned before incoming requests end.
+    pub fn close(&self) {
+        // Unlike close(2), this wakes up the thread blocked in accept(2)
+        unsafe {
+            libc::shutdown(self.fd, libc::SHUT_RDWR);
+        }
+
+        self.http.unblock();
+    }
+}
+
+impl Listener {
+    /// Checks that the address is either `unix:<path>` or `<ip>:<port>`.
+    pub fn validate(addr: &str) -> Result<(), String> {
+        if addr.starts_with(UNIX_PREFIX) {
+            return Ok(());
+        }
+
+        addr.parse::<net::SocketAddr>()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn bind(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => {
+                let path = Path::new(path);
+
+                // Socket left behind by the previous run would fail the bind
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            None => TcpListener::bind(addr).map(Listener::Tcp),
+        }
+    }
+
+    /// Returns listeners passed by systemd socket activation, if any.
+    pub fn from_systemd() -> Option<Vec<Self>> {
+        let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+        let fds = env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+
+        if pid != process::id() || fds < 1 {
+            return None;
+        }
+
+        // Children are not supposed to inherit the sockets
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        Some(
+            (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+                .map(Self::from_raw_fd)
+                .collect(),
+        )
+    }
+
+    fn from_raw_fd(fd: RawFd) -> Self {
+        // Getting the local address only succeeds for unix sockets
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+
+        if listener.local_addr().is_ok() {
+            Listener::Unix(listener)
+        } else {
+            Listener::Tcp(unsafe { TcpListener::from_raw_fd(listener.into_raw_fd()) })
+        }
+    }
+
+    pub fn serve(self, tls: Option<Arc<rustls::ServerConfig>>) -> io::Result<Server> {
+        let fd = match &self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        };
+
+        let (http, backend) = match (self, tls) {
+            (Listener::Tcp(listener), Some(config)) => tls::serve(
+                iter::from_fn(move || Some(listener.accept().map(|(s, _)| s))),
+                config,
+            )
+            .map(|(http, backend)| (http, Some(backend)))?,
+            (Listener::Unix(listener), Some(config)) => tls::serve(
+                iter::from_fn(move || Some(listener.accept().map(|(s, _)| s))),
+                config,
+            )
+            .map(|(http, backend)| (http, Some(backend)))?,
+            (Listener::Tcp(listener), None) => (
+                tiny_http::Server::from_listener(listener, None).map_err(io::Error::other)?,
+                None,
+            ),
+            (Listener::Unix(listener), None) => (
+                tiny_http::Server::from_listener(listener, None).map_err(io::Error::other)?,
+                None,
+            ),
+        };
+
+        Ok(Server {
+            http,
+            fd,
+            _backend: backend,
+        })
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "unknown tcp address"),
+            },
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .as_ref()
+                .and_then(|addr| addr.as_pathname())
+            {
+                Some(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+                None => write!(f, "{}unnamed", UNIX_PREFIX),
+            },
+        }
+    }
+}
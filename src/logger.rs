@@ -0,0 +1,203 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use log::kv;
+
+pub const FORMATS: &[&str] = &["logfmt", "json"];
+pub const LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Logger writing one line per record to stderr, in the formats
+/// used by other Prometheus exporters.
+struct Logger {
+    level: log::LevelFilter,
+    json: bool,
+}
+
+/// Installs the global logger, `level` and `format` are expected to be
+/// one of `LEVELS` and `FORMATS`.
+pub fn init(level: &str, format: &str) {
+    let level = level.parse().unwrap();
+
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        json: format == "json",
+    }))
+    .unwrap();
+
+    log::set_max_level(level);
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        level(metadata) <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = vec![
+            Field::text(
+                "ts",
+                humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            ),
+            Field::text("level", level(record.metadata()).as_str().to_lowercase()),
+            Field::text("msg", record.args().to_string()),
+        ];
+
+        let _ = record.key_values().visit(&mut Fields(&mut fields));
+
+        let mut line = if self.json {
+            json(&fields)
+        } else {
+            logfmt(&fields)
+        };
+
+        line.push('\n');
+
+        // Lines from different threads are kept whole by writing them at once
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Level to log the record at. `tiny_http` logs errors accepting clients,
+/// which are expected once listeners are shut down, so it's debug at most.
+fn level(metadata: &log::Metadata) -> log::Level {
+    if metadata.target().starts_with("tiny_http") {
+        metadata.level().max(log::Level::Debug)
+    } else {
+        metadata.level()
+    }
+}
+
+struct Field {
+    key: String,
+    value: String,
+    /// Numbers and booleans are not quoted in JSON
+    typed: bool,
+}
+
+impl Field {
+    fn text(key: &str, value: String) -> Self {
+        Field {
+            key: key.to_string(),
+            value,
+            typed: false,
+        }
+    }
+}
+
+struct Fields<'a>(&'a mut Vec<Field>);
+
+impl<'kvs> kv::VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        // Unset options like `None` are left out
+        let mut null = Null(false);
+        value.visit(&mut null)?;
+
+        if null.0 {
+            return Ok(());
+        }
+
+        let typed = value.to_bool().is_some()
+            || value.to_i64().is_some()
+            || value.to_u64().is_some()
+            || value.to_f64().map(f64::is_finite).unwrap_or(false);
+
+        self.0.push(Field {
+            key: key.to_string(),
+            value: value.to_string(),
+            typed,
+        });
+
+        Ok(())
+    }
+}
+
+struct Null(bool);
+
+impl<'v> kv::VisitValue<'v> for Null {
+    fn visit_any(&mut self, _: kv::Value) -> Result<(), kv::Error> {
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = true;
+        Ok(())
+    }
+}
+
+fn logfmt(fields: &[Field]) -> String {
+    let mut line = String::new();
+
+    for (i, Field { key, value, .. }) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+
+        let quote = value.is_empty()
+            || value
+                .chars()
+                .any(|c| c == ' ' || c == '=' || c == '"' || c.is_control());
+
+        if quote {
+            let _ = write!(line, "{}={:?}", key, value);
+        } else {
+            let _ = write!(line, "{}={}", key, value);
+        }
+    }
+
+    line
+}
+
+fn json(fields: &[Field]) -> String {
+    let mut line = String::from("{");
+
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+
+        if field.typed {
+            let _ = write!(line, "{}:{}", json_string(&field.key), field.value);
+        } else {
+            let _ = write!(
+                line,
+                "{}:{}",
+                json_string(&field.key),
+                json_string(&field.value)
+            );
+        }
+    }
+
+    line.push('}');
+    line
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
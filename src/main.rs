@@ -1,269 +1,1171 @@
 use std::collections::HashMap;
-use std::fs;
-use std::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time;
 
-use std::io::Read;
+use std::io::Write;
 
 use prometheus::Encoder;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 
-const MOUNTPOINT: &str = "/sys/fs/cgroup";
-const PRESSURE_SUFFIX: &str = ".pressure";
+mod config;
+mod listener;
+mod logger;
+mod openmetrics;
+mod tls;
+mod web_config;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "[::1]:12345";
+
+const KUBELET_REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(30);
+const KUBELET_TIMEOUT: time::Duration = time::Duration::from_secs(2);
 
 fn main() {
     let matches = clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
         .about(clap::crate_description!())
+        .arg(
+            clap::Arg::with_name("config.file")
+                .help("Path to configuration file, re-read on SIGHUP")
+                .long("config.file")
+                .env("PSI_EXPORTER_CONFIG_FILE")
+                .validator(|v| config::Config::load(Path::new(&v)).map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("log.level")
+                .help("Only log messages with the given severity or above")
+                .long("log.level")
+                .env("PSI_EXPORTER_LOG_LEVEL")
+                .possible_values(logger::LEVELS)
+                .takes_value(true)
+                .default_value("info"),
+        )
+        .arg(
+            clap::Arg::with_name("log.format")
+                .help("Output format of log messages")
+                .long("log.format")
+                .env("PSI_EXPORTER_LOG_FORMAT")
+                .possible_values(logger::FORMATS)
+                .takes_value(true)
+                .default_value("logfmt"),
+        )
         .arg(
             clap::Arg::with_name("web.listen-address")
-                .help("Address on which to expose metrics and web interface")
+                .help("Address on which to expose metrics and web interface, or unix:<path>, can be repeated [default: [::1]:12345]")
                 .long("web.listen-address")
+                .env("PSI_EXPORTER_WEB_LISTEN_ADDRESS")
+                .validator(|v| listener::Listener::validate(&v))
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("web.telemetry-path")
+                .help("Path under which to expose metrics")
+                .long("web.telemetry-path")
+                .env("PSI_EXPORTER_WEB_TELEMETRY_PATH")
+                .validator(|v| {
+                    if v.starts_with('/') {
+                        Ok(())
+                    } else {
+                        Err("path must start with /".to_string())
+                    }
+                })
+                .takes_value(true)
+                .default_value("/metrics"),
+        )
+        .arg(
+            clap::Arg::with_name("web.config.file")
+                .help("Path to web configuration file with TLS and basic auth settings")
+                .long("web.config.file")
+                .env("PSI_EXPORTER_WEB_CONFIG_FILE")
+                .validator(|v| web_config::WebConfig::load(Path::new(&v)).map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("web.workers")
+                .help("Number of threads serving requests")
+                .long("web.workers")
+                .env("PSI_EXPORTER_WEB_WORKERS")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(0) => Err("at least one worker is required".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .takes_value(true)
+                .default_value("4"),
+        )
+        .arg(
+            clap::Arg::with_name("web.shutdown-timeout")
+                .help("Time to finish in-flight requests on SIGTERM or SIGINT")
+                .long("web.shutdown-timeout")
+                .env("PSI_EXPORTER_WEB_SHUTDOWN_TIMEOUT")
                 .validator(|v| {
-                    v.parse::<net::SocketAddr>()
+                    humantime::parse_duration(&v)
                         .map(|_| ())
                         .map_err(|e| e.to_string())
                 })
                 .takes_value(true)
-                .default_value("[::1]:12345"),
+                .default_value("10s"),
+        )
+        .arg(
+            clap::Arg::with_name("web.enable-openmetrics")
+                .help("Serve OpenMetrics to scrapers asking for it, with counter samples suffixed by _total [env: PSI_EXPORTER_WEB_ENABLE_OPENMETRICS=]")
+                .long("web.enable-openmetrics")
+                .takes_value(false),
+        )
+        .arg(
+            clap::Arg::with_name("collect.interval")
+                .help("Collect in the background at this interval instead of on every scrape")
+                .long("collect.interval")
+                .env("PSI_EXPORTER_COLLECT_INTERVAL")
+                .validator(|v| match humantime::parse_duration(&v) {
+                    Ok(interval) if interval.as_nanos() == 0 => {
+                        Err("interval must be greater than zero".to_string())
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cgroup.root")
+                .help("Root of the cgroup2 hierarchy to walk, detected from mountinfo by default")
+                .long("cgroup.root")
+                .env("PSI_EXPORTER_CGROUP_ROOT")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("cgroup.include")
+                .help("Regex of cgroup ids to export, anchored, can be repeated")
+                .long("cgroup.include")
+                .env("PSI_EXPORTER_CGROUP_INCLUDE")
+                .validator(validate_regex)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("cgroup.exclude")
+                .help("Regex of cgroup ids to skip along with their children, anchored, can be repeated")
+                .long("cgroup.exclude")
+                .env("PSI_EXPORTER_CGROUP_EXCLUDE")
+                .validator(validate_regex)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("cgroup.max-depth")
+                .help("Maximum depth of cgroups to export, with 0 being the root cgroup")
+                .long("cgroup.max-depth")
+                .env("PSI_EXPORTER_CGROUP_MAX_DEPTH")
+                .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cgroup.kubernetes")
+                .long("cgroup.kubernetes")
+                .help("Parse kubepods cgroups into pod and container labels, exporting container scopes [env: PSI_EXPORTER_CGROUP_KUBERNETES=]")
+                .takes_value(false),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.url")
+                .help("Base url of kubelet to resolve names of pods from, like https://127.0.0.1:10250")
+                .long("kubelet.url")
+                .env("PSI_EXPORTER_KUBELET_URL")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.token-file")
+                .help("Path to bearer token for kubelet, like /var/run/secrets/kubernetes.io/serviceaccount/token")
+                .long("kubelet.token-file")
+                .env("PSI_EXPORTER_KUBELET_TOKEN_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.ca-file")
+                .help("Path to certificates to verify kubelet with instead of system roots")
+                .long("kubelet.ca-file")
+                .env("PSI_EXPORTER_KUBELET_CA_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.insecure-skip-verify")
+                .long("kubelet.insecure-skip-verify")
+                .help("Do not verify certificate of kubelet [env: PSI_EXPORTER_KUBELET_INSECURE_SKIP_VERIFY=]")
+                .takes_value(false),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.refresh-interval")
+                .help("Minimum time between requests to kubelet for unknown pods [default: 30s]")
+                .long("kubelet.refresh-interval")
+                .env("PSI_EXPORTER_KUBELET_REFRESH_INTERVAL")
+                .validator(|v| {
+                    humantime::parse_duration(&v)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("kubelet.timeout")
+                .help("Time to wait for kubelet before leaving names empty [default: 2s]")
+                .long("kubelet.timeout")
+                .env("PSI_EXPORTER_KUBELET_TIMEOUT")
+                .validator(|v| match humantime::parse_duration(&v) {
+                    Ok(timeout) if timeout.as_nanos() == 0 => {
+                        Err("timeout must be greater than zero".to_string())
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .takes_value(true),
         )
         .arg(
             clap::Arg::with_name("metrics.disable-avg")
-                .help("Disable reporting of average values")
                 .long("metrics.disable-avg")
+                .help("Disable reporting of average values [env: PSI_EXPORTER_METRICS_DISABLE_AVG=]")
                 .takes_value(false),
         )
         .arg(
             clap::Arg::with_name("metrics.silence-zeros")
-                .help("Do not report zero values")
                 .long("metrics.silence-zeros")
+                .help("Do not report zero values [env: PSI_EXPORTER_METRICS_SILENCE_ZEROS=]")
                 .takes_value(false),
         )
         .get_matches();
 
-    let addr = &matches.value_of("web.listen-address").unwrap();
+    logger::init(
+        matches.value_of("log.level").unwrap(),
+        matches.value_of("log.format").unwrap(),
+    );
 
-    let report_avg = !matches.is_present("metrics.disable-avg");
-    let report_zeros = !matches.is_present("metrics.silence-zeros");
+    log::info!(
+        version = clap::crate_version!(),
+        revision = env!("PSI_EXPORTER_REVISION");
+        "Starting psi_exporter"
+    );
 
-    println!("Listening address: {}", addr);
+    let config_file = matches.value_of("config.file").map(Path::new);
 
-    let server = tiny_http::Server::http(addr).unwrap();
+    let mut config = match config_file {
+        Some(path) => config::Config::load(path).unwrap(),
+        None => config::Config::default(),
+    };
 
-    let encoder = prometheus::TextEncoder::new();
-    let content_type = tiny_http::Header::from_bytes(
-        &b"Content-type"[..],
-        encoder.format_type().to_owned().as_str(),
-    )
-    .unwrap();
+    let addrs = listen_addresses(&matches, &config);
 
-    for request in server.incoming_requests() {
-        let metrics = registry(&get_service_measurements(), report_avg, report_zeros).gather();
-        let mut buffer = vec![];
-        encoder.encode(&metrics, &mut buffer).unwrap();
+    let kubelet = kubelet(&matches, &config).unwrap_or_else(|e| {
+        log::error!(err = e.as_str(); "Error configuring kubelet");
+        process::exit(1);
+    });
 
-        request
-            .respond(tiny_http::Response::from_data(buffer).with_header(content_type.clone()))
-            .unwrap_or_else(|e| eprintln!("error responding: {}", e));
+    let options = options(&matches, &config, kubelet.clone());
+
+    let exporter = Exporter {
+        collector: match matches.value_of("collect.interval") {
+            Some(_) => psi_exporter::Collector::background(options),
+            None => psi_exporter::Collector::new(options),
+        },
+        telemetry_path: matches.value_of("web.telemetry-path").unwrap().to_string(),
+        web_config: match matches.value_of("web.config.file") {
+            Some(path) => web_config::WebConfig::load(Path::new(path)).unwrap(),
+            None => web_config::WebConfig::default(),
+        },
+        metrics: ExporterMetrics::new(),
+        encoded: Mutex::new(None),
+        openmetrics: is_enabled(
+            &matches,
+            "web.enable-openmetrics",
+            "PSI_EXPORTER_WEB_ENABLE_OPENMETRICS",
+        ),
+        kubelet,
+    };
+
+    let interval = matches
+        .value_of("collect.interval")
+        .map(|v| humantime::parse_duration(v).unwrap());
+
+    let workers: usize = matches.value_of("web.workers").unwrap().parse().unwrap();
+
+    let shutdown_timeout =
+        humantime::parse_duration(matches.value_of("web.shutdown-timeout").unwrap()).unwrap();
+
+    // Handling signals from here on keeps them from killing the process
+    // in the middle of a response
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT, SIGHUP]).unwrap();
+
+    if let Some(path) = config_file {
+        log::info!(path:% = path.display(); "Loaded config file");
     }
-}
 
-fn registry(
-    service_measurements: &HashMap<String, PsiMeasurements>,
-    report_avg: bool,
-    report_zeros: bool,
-) -> prometheus::Registry {
-    let registry = prometheus::Registry::new();
-    let labels = &["id", "controller", "kind"];
-
-    let total = counter_vec(
-        "pressure_total_seconds",
-        "Total time spent under pressure",
-        labels,
-    );
+    log::info!(path = exporter.telemetry_path.as_str(); "Serving metrics");
 
-    registry.register(Box::new(total.clone())).unwrap();
+    log_options(&exporter.collector.options());
 
-    let avg10 = gauge_vec(
-        "pressure_avg_10s_ratio",
-        "Ratio of time spent under pressure in the last 10s at time of measurement",
-        labels,
-    );
+    let listeners = match listener::Listener::from_systemd() {
+        Some(listeners) => {
+            for listener in &listeners {
+                log::info!(address:% = listener, source = "systemd"; "Listening");
+            }
 
-    let avg60 = gauge_vec(
-        "pressure_avg_60s_ratio",
-        "Ratio of time spent under pressure in the last 60s at time of measurement",
-        labels,
-    );
+            listeners
+        }
+        None => addrs
+            .iter()
+            .map(|addr| {
+                let listener = listener::Listener::bind(addr).unwrap_or_else(|e| {
+                    log::error!(address = addr.as_str(), err:% = e; "Error listening");
+                    process::exit(1);
+                });
 
-    let avg300 = gauge_vec(
-        "pressure_avg_300s_ratio",
-        "Ratio of time spent under pressure in the last 300s at time of measurement",
-        labels,
-    );
+                // Port 0 is only known once bound
+                log::info!(address:% = listener; "Listening");
+
+                listener
+            })
+            .collect(),
+    };
 
-    let averages = vec![&avg10, &avg60, &avg300];
+    let tls = exporter.web_config.tls_config().unwrap();
 
-    for metric in averages {
-        registry.register(Box::new(metric.clone())).unwrap();
+    if tls.is_some() {
+        log::info!("TLS is enabled");
     }
 
-    for (service, measurements) in service_measurements {
-        let controllers = maplit::hashmap! {
-            "cpu"    => &measurements.cpu,
-            "memory" => &measurements.memory,
-            "io"     => &measurements.io,
-        };
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| Arc::new(listener.serve(tls.clone()).unwrap()))
+        .collect();
 
-        for (controller, measurement) in controllers {
-            let kinds = maplit::hashmap! {
-                "some" => measurement.some.as_ref(),
-                "full" => measurement.full.as_ref(),
-            };
+    let exporter = Arc::new(exporter);
+
+    if let Some(interval) = interval {
+        log::info!(
+            interval:% = humantime::format_duration(interval);
+            "Collecting in the background"
+        );
 
-            for (kind, data) in kinds {
-                let labels = &[service.as_str(), controller, kind];
+        // Serve a complete snapshot from the very first scrape
+        exporter.collector.refresh();
 
-                if data == None {
-                    continue;
-                }
+        let exporter = exporter.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            exporter.collector.refresh();
+        });
+    }
+
+    // Requests from every listener are served by the same pool of workers
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let receiver = Arc::new(Mutex::new(receiver));
 
-                let data = data.unwrap();
+    for server in &servers {
+        let server = server.clone();
+        let sender = sender.clone();
 
-                if report_zeros || data.total.as_nanos() > 0 {
-                    total
-                        .with_label_values(labels)
-                        .inc_by((data.total.as_nanos() as f64) / 1e9);
+        thread::spawn(move || {
+            for request in server.http.incoming_requests() {
+                if sender.send(request).is_err() {
+                    break;
                 }
+            }
+        });
+    }
 
-                if report_avg {
-                    if report_zeros || data.avg10 > 0.0 {
-                        avg10
-                            .with_label_values(labels)
-                            .set(f64::from(data.avg10) / 100.0);
-                    }
+    // Only forwarders keep the channel open, so that workers stop once
+    // every forwarder is done
+    drop(sender);
 
-                    if report_zeros || data.avg60 > 0.0 {
-                        avg60
-                            .with_label_values(labels)
-                            .set(f64::from(data.avg60) / 100.0);
-                    }
+    let (done_sender, done_receiver) = mpsc::channel();
 
-                    if report_zeros || data.avg300 > 0.0 {
-                        avg300
-                            .with_label_values(labels)
-                            .set(f64::from(data.avg300) / 100.0);
-                    }
-                }
+    for _ in 0..workers {
+        let receiver = receiver.clone();
+        let exporter = exporter.clone();
+        let done_sender = done_sender.clone();
+
+        thread::spawn(move || {
+            loop {
+                let request = match receiver.lock().unwrap().recv() {
+                    Ok(request) => request,
+                    Err(_) => break,
+                };
+
+                exporter.handle(request);
             }
+
+            let _ = done_sender.send(());
+        });
+    }
+
+    let signal = loop {
+        match signals.forever().next().unwrap() {
+            SIGHUP => match config_file {
+                Some(path) => exporter.reload(path, &matches, &mut config),
+                None => log::warn!("Received SIGHUP without a config file, nothing to reload"),
+            },
+            signal => break signal,
         }
+    };
+
+    log::info!(
+        signal = if signal == SIGTERM { "SIGTERM" } else { "SIGINT" };
+        "Shutting down"
+    );
+
+    // New connections are refused, requests accepted by now are still
+    // served before forwarders stop
+    for server in &servers {
+        server.close();
+    }
+
+    let deadline = time::Instant::now() + shutdown_timeout;
+
+    let stopped = (0..workers)
+        .take_while(|_| {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            done_receiver.recv_timeout(timeout).is_ok()
+        })
+        .count();
+
+    if stopped < workers {
+        log::warn!(in_flight = workers - stopped; "Shutdown timed out");
+    } else {
+        log::info!("Shutdown complete");
+    }
+
+    // Removes unix sockets and directories of TLS backends
+    drop(servers);
+
+    process::exit(0);
+}
+
+/// Listen addresses from command line, the config file or the default.
+fn listen_addresses(matches: &clap::ArgMatches, config: &config::Config) -> Vec<String> {
+    match values_of(matches, "web.listen-address") {
+        Some(values) => values.into_iter().map(|addr| addr.to_string()).collect(),
+        None if !config.web.listen_addresses.is_empty() => config.web.listen_addresses.clone(),
+        None => vec![DEFAULT_LISTEN_ADDRESS.to_string()],
     }
+}
 
-    registry
+/// Values of a repeatable flag from command line, or from the environment
+/// if the flag is not given.
+fn values_of<'a>(matches: &'a clap::ArgMatches, name: &str) -> Option<Vec<&'a str>> {
+    let values = matches.values_of(name)?;
+
+    // Clap appends the value from the environment to values from command line
+    match matches.occurrences_of(name) {
+        0 => Some(values.collect()),
+        n => Some(values.take(n as usize).collect()),
+    }
 }
 
-fn counter_vec(name: &str, help: &str, labels: &[&str]) -> prometheus::CounterVec {
-    prometheus::CounterVec::new(prometheus::opts!(name, help), labels).unwrap()
+/// Whether a switch is given on command line or enabled in the environment.
+fn is_enabled(matches: &clap::ArgMatches, name: &str, env: &str) -> bool {
+    matches.is_present(name)
+        || std::env::var(env)
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false)
 }
 
-fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> prometheus::GaugeVec {
-    prometheus::GaugeVec::new(prometheus::opts!(name, help), labels).unwrap()
+fn validate_regex(value: String) -> Result<(), String> {
+    regex::Regex::new(&value)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
 }
 
-macro_rules! skip_fail {
-    ($res:expr) => {
-        match $res {
-            Ok(val) => val,
-            Err(_) => continue,
-        }
-    };
+fn regex_set<'a>(values: impl Iterator<Item = &'a str>) -> regex::RegexSet {
+    regex::RegexSet::new(values.map(|v| format!("^(?:{})$", v))).unwrap()
 }
 
-fn get_service_measurements() -> HashMap<String, PsiMeasurements> {
-    let mut services: HashMap<_, PsiMeasurements> = HashMap::new();
+struct Exporter {
+    collector: psi_exporter::Collector,
+    telemetry_path: String,
+    web_config: web_config::WebConfig,
+    metrics: ExporterMetrics,
+    encoded: Mutex<Option<Encoded>>,
+    openmetrics: bool,
+    /// Kept across reloads along with its cache
+    kubelet: Option<Arc<psi_exporter::kubelet::Resolver>>,
+}
 
-    for entry in walkdir::WalkDir::new(MOUNTPOINT)
-        .into_iter()
-        .filter_entry(|e| is_interesting(e))
-        .filter(|e| is_pressure(&e.as_ref().unwrap()))
-    {
-        let entry = entry.unwrap();
-        let path = entry.path();
-
-        let dir_name = std::path::Path::new("/")
-            .join(path.parent().unwrap().strip_prefix(MOUNTPOINT).unwrap())
-            .to_str()
+/// Bodies encoded from a collection by format and compression, served again
+/// to scrapes of the same collection instead of encoding it anew.
+struct Encoded {
+    measurements: Arc<psi_exporter::Measurements>,
+    bodies: HashMap<(Format, Compression), Vec<u8>>,
+}
+
+impl Exporter {
+    fn handle(&self, request: tiny_http::Request) {
+        let path = request.url().split('?').next().unwrap_or_default();
+
+        let response = if !self
+            .web_config
+            .is_authorized(header_value(&request, "Authorization"))
+        {
+            tiny_http::Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic"[..]).unwrap(),
+                )
+        } else if path == self.telemetry_path {
+            self.scrape(
+                Format::negotiate(header_value(&request, "Accept"), self.openmetrics),
+                Compression::negotiate(header_value(&request, "Accept-Encoding")),
+            )
+        } else if path == "/" {
+            self.landing_page()
+        } else if path == "/healthz" {
+            tiny_http::Response::from_string("OK")
+        } else {
+            tiny_http::Response::from_string("Not Found").with_status_code(404)
+        };
+
+        request
+            .respond(response)
+            .unwrap_or_else(|e| log::warn!(err:% = e; "Error responding"));
+    }
+
+    fn scrape(
+        &self,
+        format: Format,
+        compression: Compression,
+    ) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+        let start = time::Instant::now();
+
+        let measurements = self.collector.measurements();
+
+        let body = self.body(&measurements, format, compression);
+
+        log::debug!(
+            duration_seconds = start.elapsed().as_secs_f64(),
+            bytes = body.len();
+            "Served scrape"
+        );
+
+        let response = tiny_http::Response::from_data(body)
+            .with_header(format.content_type())
+            .with_header(
+                tiny_http::Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap(),
+            );
+
+        match compression.content_encoding() {
+            Some(encoding) => response.with_header(
+                tiny_http::Header::from_bytes(&b"Content-Encoding"[..], encoding).unwrap(),
+            ),
+            None => response,
+        }
+    }
+
+    /// Reuses the body encoded from the same collection, like the snapshot of
+    /// background collection, or encodes measurements in the format.
+    fn body(
+        &self,
+        measurements: &Arc<psi_exporter::Measurements>,
+        format: Format,
+        compression: Compression,
+    ) -> Vec<u8> {
+        let mut encoded = self.encoded.lock().unwrap();
+
+        let stale = encoded
+            .as_ref()
+            .map(|encoded| !Arc::ptr_eq(&encoded.measurements, measurements))
+            .unwrap_or(true);
+
+        if stale {
+            *encoded = Some(Encoded {
+                measurements: measurements.clone(),
+                bodies: HashMap::new(),
+            });
+        }
+
+        encoded
+            .as_mut()
             .unwrap()
-            .to_string();
+            .bodies
+            .entry((format, compression))
+            .or_insert_with(|| self.encode(measurements, format, compression))
+            .clone()
+    }
 
-        let mut controller = path.file_name().unwrap().to_str().unwrap().to_string();
+    fn encode(
+        &self,
+        measurements: &psi_exporter::Measurements,
+        format: Format,
+        compression: Compression,
+    ) -> Vec<u8> {
+        let mut families = self.collector.families(measurements);
+        families.extend(self.metrics.registry.gather());
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        let mut buffer = vec![];
+
+        match format {
+            Format::Text => {
+                let encoder = prometheus::TextEncoder::new();
+                encoder.encode(&families, &mut buffer).unwrap();
+            }
+            Format::OpenMetrics => {
+                let created = |family: &str, metric: &prometheus::proto::Metric| {
+                    if family == "pressure_total_seconds" {
+                        created_timestamp(measurements, metric)
+                    } else {
+                        None
+                    }
+                };
 
-        controller.truncate(controller.len() - PRESSURE_SUFFIX.len());
+                openmetrics::encode(&families, created, &mut buffer).unwrap();
+            }
+            Format::Protobuf => {
+                let encoder = prometheus::ProtobufEncoder::new();
+                encoder.encode(&families, &mut buffer).unwrap();
+            }
+        }
 
-        let mut file = skip_fail!(fs::OpenOptions::new().read(true).open(path));
-        let mut buf = String::with_capacity(256);
-        skip_fail!(file.read_to_string(&mut buf));
+        compression.compress(buffer).unwrap()
+    }
 
-        let mut some = None;
-        let mut full = None;
+    /// Re-reads the config file and applies it to the following collections,
+    /// the current config is kept if the file is invalid.
+    fn reload(&self, path: &Path, matches: &clap::ArgMatches, current: &mut config::Config) {
+        let config = match config::Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!(err = e.as_str(); "Error reloading config");
+                self.metrics.config_last_reload_successful.set(0);
+                self.forget_encoded();
+                return;
+            }
+        };
 
-        for line in buf.lines() {
-            let parsed: Result<psi::Psi, _> = line.parse();
-            let parsed = parsed.unwrap();
+        if config.web.listen_addresses != current.web.listen_addresses {
+            log::warn!("Listen addresses changed, restart to apply");
+        }
 
-            match parsed.line {
-                psi::PsiLine::Some => some = Some(parsed),
-                psi::PsiLine::Full => full = Some(parsed),
-            };
+        if config.kubelet != current.kubelet {
+            log::warn!("Kubelet settings changed, restart to apply");
         }
 
-        populate_measurements(
-            &controller,
-            services.entry(dir_name).or_default(),
-            PsiStats { some, full },
+        self.collector
+            .set_options(options(matches, &config, self.kubelet.clone()));
+        self.metrics.config_last_reload_successful.set(1);
+        self.forget_encoded();
+
+        log::info!(path:% = path.display(); "Reloaded config");
+        log_options(&self.collector.options());
+
+        *current = config;
+    }
+
+    /// Drops bodies encoded before options or metrics of the exporter changed.
+    fn forget_encoded(&self) {
+        *self.encoded.lock().unwrap() = None;
+    }
+
+    fn landing_page(&self) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+        let html = format!(
+            "<html>\n\
+             <head><title>PSI Exporter</title></head>\n\
+             <body>\n\
+             <h1>PSI Exporter</h1>\n\
+             <p><a href=\"{}\">Metrics</a></p>\n\
+             </body>\n\
+             </html>\n",
+            self.telemetry_path
         );
+
+        tiny_http::Response::from_string(html).with_header(content_type("text/html; charset=utf-8"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl Format {
+    fn content_type(self) -> tiny_http::Header {
+        match self {
+            Format::Text => content_type(prometheus::TextEncoder::new().format_type()),
+            Format::OpenMetrics => content_type(openmetrics::FORMAT),
+            Format::Protobuf => content_type(prometheus::ProtobufEncoder::new().format_type()),
+        }
+    }
+
+    /// Picks the format for the value of `Accept` header, passing over
+    /// OpenMetrics unless it's enabled.
+    fn negotiate(accept: &str, openmetrics: bool) -> Self {
+        let mut best = (Format::Text, 0.0);
+
+        for range in MediaRange::parse_all(accept) {
+            let format = match range.value {
+                "application/openmetrics-text" if openmetrics => Format::OpenMetrics,
+                "application/vnd.google.protobuf"
+                    if range.param("proto") == Some("io.prometheus.client.MetricFamily")
+                        && range.param("encoding") == Some("delimited") =>
+                {
+                    Format::Protobuf
+                }
+                "text/plain" => Format::Text,
+                _ => continue,
+            };
+
+            // Earlier ranges win ties, as scrapers list their preferred formats first
+            if range.quality > best.1 {
+                best = (format, range.quality);
+            }
+        }
+
+        best.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Compression {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl Compression {
+    /// Picks the compression for the value of `Accept-Encoding` header.
+    fn negotiate(accept_encoding: &str) -> Self {
+        let mut best = (Compression::Identity, 0.0);
+
+        for range in MediaRange::parse_all(accept_encoding) {
+            let compression = match range.value {
+                "gzip" | "x-gzip" => Compression::Gzip,
+                "deflate" => Compression::Deflate,
+                _ => continue,
+            };
+
+            if range.quality > best.1 {
+                best = (compression, range.quality);
+            }
+        }
+
+        best.0
+    }
+
+    fn compress(self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+
+        match self {
+            Compression::Identity => Ok(data),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], level);
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            Compression::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(vec![], level);
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+        }
     }
 
-    services
+    fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::Identity => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Deflate => Some("deflate"),
+        }
+    }
 }
 
-fn populate_measurements(
-    controller: &str,
-    measurements: &mut PsiMeasurements,
-    measurement: PsiStats,
-) {
-    match controller {
-        "cpu" => measurements.cpu = measurement,
-        "memory" => measurements.memory = measurement,
-        "io" => measurements.io = measurement,
-        _ => (),
+/// Single element of a comma separated header value with parameters,
+/// as found in `Accept` and `Accept-Encoding` headers.
+struct MediaRange<'a> {
+    value: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+    quality: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse_all(header: &'a str) -> impl Iterator<Item = MediaRange<'a>> {
+        header.split(',').map(|range| {
+            let mut parts = range.split(';').map(|p| p.trim());
+            let value = parts.next().unwrap_or_default();
+
+            let params: Vec<_> = parts
+                .filter_map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    Some((kv.next()?.trim(), kv.next()?.trim()))
+                })
+                .collect();
+
+            let quality = params
+                .iter()
+                .find(|(k, _)| *k == "q")
+                .and_then(|(_, q)| q.parse().ok())
+                .unwrap_or(1.0);
+
+            MediaRange {
+                value,
+                params,
+                quality,
+            }
+        })
+    }
+
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| *v)
     }
 }
 
-fn is_interesting(entry: &walkdir::DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| !(s.ends_with(".mount") || s.ends_with(".socket") || s.ends_with(".scope")))
-        .unwrap_or(false)
+fn header_value<'a>(request: &'a tiny_http::Request, name: &'static str) -> &'a str {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+        .unwrap_or_default()
 }
 
-fn is_pressure(entry: &walkdir::DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.ends_with(PRESSURE_SUFFIX))
-        .unwrap_or(false)
+fn created_timestamp(
+    measurements: &psi_exporter::Measurements,
+    metric: &prometheus::proto::Metric,
+) -> Option<f64> {
+    let label = |name| {
+        metric
+            .get_label()
+            .iter()
+            .find(|l| l.get_name() == name)
+            .map(|l| l.get_value())
+    };
+
+    measurements
+        .get(label("id")?)?
+        .controllers
+        .get(label("controller")?)?
+        .created
+        .and_then(|created| created.duration_since(time::UNIX_EPOCH).ok())
+        .map(|created| created.as_secs_f64())
+}
+
+fn content_type(value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(&b"Content-Type"[..], value).unwrap()
 }
 
-#[derive(Debug, Default)]
-struct PsiStats {
-    some: Option<psi::Psi>,
-    full: Option<psi::Psi>,
+/// Metrics of the exporter itself, on top of those from the collector.
+struct ExporterMetrics {
+    registry: prometheus::Registry,
+    config_last_reload_successful: prometheus::IntGauge,
 }
 
-#[derive(Debug, Default)]
-struct PsiMeasurements {
-    cpu: PsiStats,
-    memory: PsiStats,
-    io: PsiStats,
+impl ExporterMetrics {
+    fn new() -> Self {
+        let build_info = prometheus::IntGaugeVec::new(
+            prometheus::opts!(
+                "psi_exporter_build_info",
+                "Build information with version and git revision, always 1"
+            ),
+            &["version", "revision"],
+        )
+        .unwrap();
+
+        build_info
+            .with_label_values(&[clap::crate_version!(), env!("PSI_EXPORTER_REVISION")])
+            .set(1);
+
+        let config_last_reload_successful = prometheus::IntGauge::new(
+            "psi_exporter_config_last_reload_successful",
+            "Whether the last config reload attempt was successful",
+        )
+        .unwrap();
+
+        config_last_reload_successful.set(1);
+
+        let registry = prometheus::Registry::new();
+
+        registry.register(Box::new(build_info)).unwrap();
+        registry
+            .register(Box::new(config_last_reload_successful.clone()))
+            .unwrap();
+
+        ExporterMetrics {
+            registry,
+            config_last_reload_successful,
+        }
+    }
+}
+
+fn log_options(options: &psi_exporter::Options) {
+    for root in &options.cgroups.roots {
+        log::info!(root:% = root.display(); "Walking cgroup root");
+    }
+
+    let patterns = |set: &Option<regex::RegexSet>| {
+        set.as_ref()
+            .map(|set| set.patterns().join(" "))
+            .unwrap_or_default()
+    };
+
+    log::info!(
+        include = patterns(&options.cgroups.include).as_str(),
+        exclude = patterns(&options.cgroups.exclude).as_str(),
+        max_depth = options.cgroups.max_depth,
+        relabel_rules = options.cgroups.relabel.len(),
+        kubernetes = options.cgroups.kubernetes,
+        report_avg = options.report_avg,
+        report_zeros = options.report_zeros;
+        "Collection options"
+    );
+
+    if let Some(kubelet) = &options.cgroups.kubelet {
+        log::info!(url = kubelet.url(); "Resolving pod names through kubelet");
+    }
+}
+
+/// Creates resolver of pod names from command line flags and the config
+/// file, if kubelet url is given in either.
+fn kubelet(
+    matches: &clap::ArgMatches,
+    config: &config::Config,
+) -> Result<Option<Arc<psi_exporter::kubelet::Resolver>>, String> {
+    let url = match matches.value_of("kubelet.url") {
+        Some(url) => url.to_string(),
+        None => match &config.kubelet.url {
+            Some(url) => url.clone(),
+            None => return Ok(None),
+        },
+    };
+
+    let path = |name, fallback: &Option<PathBuf>| {
+        matches
+            .value_of(name)
+            .map(PathBuf::from)
+            .or_else(|| fallback.clone())
+    };
+
+    let refresh_interval = matches
+        .value_of("kubelet.refresh-interval")
+        .or(config.kubelet.refresh_interval.as_deref())
+        .map(|v| humantime::parse_duration(v).unwrap())
+        .unwrap_or(KUBELET_REFRESH_INTERVAL);
+
+    let timeout = matches
+        .value_of("kubelet.timeout")
+        .or(config.kubelet.timeout.as_deref())
+        .map(|v| humantime::parse_duration(v).unwrap())
+        .unwrap_or(KUBELET_TIMEOUT);
+
+    let resolver = psi_exporter::kubelet::Resolver::new(psi_exporter::kubelet::Endpoint {
+        url,
+        token_file: path("kubelet.token-file", &config.kubelet.token_file),
+        ca_file: path("kubelet.ca-file", &config.kubelet.ca_file),
+        insecure_skip_verify: is_enabled(
+            matches,
+            "kubelet.insecure-skip-verify",
+            "PSI_EXPORTER_KUBELET_INSECURE_SKIP_VERIFY",
+        ) || config.kubelet.insecure_skip_verify,
+        timeout,
+        refresh_interval,
+    })?;
+
+    Ok(Some(Arc::new(resolver)))
+}
+
+/// Combines command line flags with the config file, flags win.
+fn options(
+    matches: &clap::ArgMatches,
+    config: &config::Config,
+    kubelet: Option<Arc<psi_exporter::kubelet::Resolver>>,
+) -> psi_exporter::Options {
+    let values = |name, fallback: &[String]| -> Option<Vec<String>> {
+        match values_of(matches, name) {
+            Some(values) => Some(values.into_iter().map(|v| v.to_string()).collect()),
+            None if !fallback.is_empty() => Some(fallback.to_vec()),
+            None => None,
+        }
+    };
+
+    let roots = match values_of(matches, "cgroup.root") {
+        Some(values) => values.into_iter().map(PathBuf::from).collect(),
+        None if !config.cgroup.roots.is_empty() => config.cgroup.roots.clone(),
+        None => vec![psi_exporter::detect_cgroup_root()],
+    };
+
+    psi_exporter::Options {
+        cgroups: psi_exporter::CgroupOptions {
+            roots,
+            include: values("cgroup.include", &config.cgroup.include)
+                .map(|v| regex_set(v.iter().map(String::as_str))),
+            exclude: values("cgroup.exclude", &config.cgroup.exclude)
+                .map(|v| regex_set(v.iter().map(String::as_str))),
+            max_depth: matches
+                .value_of("cgroup.max-depth")
+                .map(|v| v.parse().unwrap())
+                .or(config.cgroup.max_depth),
+            relabel: config
+                .cgroup
+                .relabel
+                .iter()
+                .map(|rule| {
+                    let regex = regex::Regex::new(&format!("^(?:{})$", rule.regex)).unwrap();
+                    (regex, rule.replacement.clone())
+                })
+                .collect(),
+            kubernetes: is_enabled(
+                matches,
+                "cgroup.kubernetes",
+                "PSI_EXPORTER_CGROUP_KUBERNETES",
+            ) || config.cgroup.kubernetes
+                || kubelet.is_some(),
+            kubelet,
+        },
+        system_pressure: Some(PathBuf::from(psi_exporter::SYSTEM_PRESSURE_DIR)),
+        report_avg: !(is_enabled(
+            matches,
+            "metrics.disable-avg",
+            "PSI_EXPORTER_METRICS_DISABLE_AVG",
+        ) || config.metrics.disable_avg),
+        report_zeros: !(is_enabled(
+            matches,
+            "metrics.silence-zeros",
+            "PSI_EXPORTER_METRICS_SILENCE_ZEROS",
+        ) || config.metrics.silence_zeros),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_ranges() {
+        let header = "text/plain;q=0.5, application/vnd.google.protobuf; \
+                      proto=io.prometheus.client.MetricFamily; encoding=delimited,*/*;q=bogus";
+
+        let ranges: Vec<_> = MediaRange::parse_all(header).collect();
+
+        assert_eq!(ranges.len(), 3);
+
+        assert_eq!(ranges[0].value, "text/plain");
+        assert_eq!(ranges[0].quality, 0.5);
+
+        assert_eq!(ranges[1].value, "application/vnd.google.protobuf");
+        assert_eq!(ranges[1].quality, 1.0);
+        assert_eq!(
+            ranges[1].param("proto"),
+            Some("io.prometheus.client.MetricFamily")
+        );
+        assert_eq!(ranges[1].param("encoding"), Some("delimited"));
+        assert_eq!(ranges[1].param("q"), None);
+
+        // Unparsable quality counts as the default
+        assert_eq!(ranges[2].value, "*/*");
+        assert_eq!(ranges[2].quality, 1.0);
+    }
+
+    #[test]
+    fn format_negotiation() {
+        const PROTOBUF: &str = "application/vnd.google.protobuf;\
+                                proto=io.prometheus.client.MetricFamily;encoding=delimited";
+        const PROMETHEUS: &str =
+            "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+        assert_eq!(Format::negotiate("", true), Format::Text);
+        assert_eq!(Format::negotiate("*/*", true), Format::Text);
+        assert_eq!(Format::negotiate("text/plain", true), Format::Text);
+
+        assert_eq!(Format::negotiate(PROMETHEUS, true), Format::OpenMetrics);
+        assert_eq!(Format::negotiate(PROTOBUF, true), Format::Protobuf);
+
+        // OpenMetrics is passed over unless enabled
+        assert_eq!(Format::negotiate(PROMETHEUS, false), Format::Text);
+        assert_eq!(
+            Format::negotiate(
+                &format!("application/openmetrics-text,{};q=0.5", PROTOBUF),
+                false
+            ),
+            Format::Protobuf
+        );
+
+        // Ties go to the range listed first
+        assert_eq!(
+            Format::negotiate("text/plain;q=0.5,application/openmetrics-text;q=0.5", true),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate(&format!("{};q=0.5,text/plain;q=0.5", PROTOBUF), true),
+            Format::Protobuf
+        );
+
+        // Ranges with zero quality are not acceptable
+        assert_eq!(
+            Format::negotiate("application/openmetrics-text;q=0", true),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate("application/openmetrics-text;q=0,text/plain;q=0.1", true),
+            Format::Text
+        );
+
+        // Protobuf is only served as delimited MetricFamily messages
+        assert_eq!(
+            Format::negotiate("application/vnd.google.protobuf", true),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text",
+                true
+            ),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate(
+                "application/vnd.google.protobuf;proto=foo.Bar;encoding=delimited",
+                true
+            ),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn compression_negotiation() {
+        assert_eq!(Compression::negotiate(""), Compression::Identity);
+        assert_eq!(Compression::negotiate("identity"), Compression::Identity);
+        assert_eq!(Compression::negotiate("br"), Compression::Identity);
+
+        assert_eq!(Compression::negotiate("gzip"), Compression::Gzip);
+        assert_eq!(Compression::negotiate("x-gzip"), Compression::Gzip);
+        assert_eq!(
+            Compression::negotiate("br, deflate;q=0.9, gzip;q=0.5"),
+            Compression::Deflate
+        );
+
+        // Ties go to the encoding listed first
+        assert_eq!(
+            Compression::negotiate("deflate, gzip"),
+            Compression::Deflate
+        );
+        assert_eq!(
+            Compression::negotiate("gzip;q=0.8, deflate;q=0.8"),
+            Compression::Gzip
+        );
+
+        // Encodings with zero quality are not acceptable
+        assert_eq!(Compression::negotiate("gzip;q=0"), Compression::Identity);
+        assert_eq!(
+            Compression::negotiate("gzip;q=0, deflate;q=0.1"),
+            Compression::Deflate
+        );
+    }
 }
@@ -0,0 +1,245 @@
+use std::io::{self, Write};
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+pub const FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Units that are recognized as metric name suffixes and announced in `# UNIT`.
+const UNITS: &[&str] = &["seconds", "ratio"];
+
+/// Encodes metric families in OpenMetrics text format.
+///
+/// The `created` callback provides optional `_created` timestamps for counters
+/// as unix time in seconds, it is called with metric family name and metric.
+pub fn encode<W, F>(families: &[MetricFamily], created: F, writer: &mut W) -> io::Result<()>
+where
+    W: Write,
+    F: Fn(&str, &Metric) -> Option<f64>,
+{
+    for family in families {
+        let name = family.get_name();
+
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => (name.trim_end_matches("_total"), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            // Not produced by this exporter
+            _ => continue,
+        };
+
+        writeln!(writer, "# TYPE {} {}", family_name, kind)?;
+
+        if let Some(unit) = UNITS
+            .iter()
+            .find(|unit| family_name.ends_with(&format!("_{}", unit)))
+        {
+            writeln!(writer, "# UNIT {} {}", family_name, unit)?;
+        }
+
+        writeln!(
+            writer,
+            "# HELP {} {}",
+            family_name,
+            escape(family.get_help())
+        )?;
+
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", family_name);
+                    write_sample(writer, &sample, metric, metric.get_counter().get_value())?;
+
+                    if let Some(created) = created(name, metric) {
+                        let sample = format!("{}_created", family_name);
+                        write_sample(writer, &sample, metric, created)?;
+                    }
+                }
+                _ => write_sample(writer, name, metric, metric.get_gauge().get_value())?,
+            }
+        }
+    }
+
+    writeln!(writer, "# EOF")
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    metric: &Metric,
+    value: f64,
+) -> io::Result<()> {
+    write!(writer, "{}", name)?;
+    write_labels(writer, metric.get_label())?;
+    writeln!(writer, " {}", format_value(value))
+}
+
+fn write_labels<W: Write>(writer: &mut W, labels: &[LabelPair]) -> io::Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    write!(writer, "{{")?;
+
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 {
+            write!(writer, ",")?;
+        }
+
+        write!(
+            writer,
+            "{}=\"{}\"",
+            label.get_name(),
+            escape(label.get_value())
+        )?;
+    }
+
+    write!(writer, "}}")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(name: &str, help: &str, kind: MetricType, metrics: Vec<Metric>) -> MetricFamily {
+        let mut family = MetricFamily::default();
+        family.set_name(name.to_string());
+        family.set_help(help.to_string());
+        family.set_field_type(kind);
+        family.set_metric(metrics.into_iter().collect());
+        family
+    }
+
+    fn metric(labels: &[(&str, &str)], kind: MetricType, value: f64) -> Metric {
+        let mut metric = Metric::default();
+
+        metric.set_label(
+            labels
+                .iter()
+                .map(|(name, value)| {
+                    let mut label = LabelPair::default();
+                    label.set_name(name.to_string());
+                    label.set_value(value.to_string());
+                    label
+                })
+                .collect(),
+        );
+
+        match kind {
+            MetricType::COUNTER => {
+                let mut counter = prometheus::proto::Counter::default();
+                counter.set_value(value);
+                metric.set_counter(counter);
+            }
+            _ => {
+                let mut gauge = prometheus::proto::Gauge::default();
+                gauge.set_value(value);
+                metric.set_gauge(gauge);
+            }
+        }
+
+        metric
+    }
+
+    #[test]
+    fn exposition() {
+        let families = vec![
+            family(
+                "pressure_total_seconds",
+                "Total time spent under pressure",
+                MetricType::COUNTER,
+                vec![
+                    metric(
+                        &[("controller", "cpu"), ("id", "/"), ("kind", "some")],
+                        MetricType::COUNTER,
+                        1.5,
+                    ),
+                    metric(
+                        &[
+                            ("controller", "cpu"),
+                            ("id", "/a\\b\"c\nd"),
+                            ("kind", "some"),
+                        ],
+                        MetricType::COUNTER,
+                        0.0,
+                    ),
+                ],
+            ),
+            family(
+                "psi_exporter_pressure_files_read_total",
+                "Number of pressure files successfully read",
+                MetricType::COUNTER,
+                vec![metric(&[], MetricType::COUNTER, 3.0)],
+            ),
+            family(
+                "psi_exporter_scrape_duration_seconds",
+                "Time spent \"collecting\"\nin seconds",
+                MetricType::GAUGE,
+                vec![metric(&[], MetricType::GAUGE, 0.25)],
+            ),
+            family(
+                "pressure_avg_10s_ratio",
+                "Ratio of time spent under pressure",
+                MetricType::GAUGE,
+                vec![metric(&[("id", "/")], MetricType::GAUGE, f64::NAN)],
+            ),
+        ];
+
+        let created = |name: &str, metric: &Metric| {
+            if name == "pressure_total_seconds" && metric.get_label()[1].get_value() == "/" {
+                Some(1700000000.5)
+            } else {
+                None
+            }
+        };
+
+        let mut buffer = vec![];
+        encode(&families, created, &mut buffer).unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            r#"# TYPE pressure_total_seconds counter
+# UNIT pressure_total_seconds seconds
+# HELP pressure_total_seconds Total time spent under pressure
+pressure_total_seconds_total{controller="cpu",id="/",kind="some"} 1.5
+pressure_total_seconds_created{controller="cpu",id="/",kind="some"} 1700000000.5
+pressure_total_seconds_total{controller="cpu",id="/a\\b\"c\nd",kind="some"} 0
+# TYPE psi_exporter_pressure_files_read counter
+# HELP psi_exporter_pressure_files_read Number of pressure files successfully read
+psi_exporter_pressure_files_read_total 3
+# TYPE psi_exporter_scrape_duration_seconds gauge
+# UNIT psi_exporter_scrape_duration_seconds seconds
+# HELP psi_exporter_scrape_duration_seconds Time spent \"collecting\"\nin seconds
+psi_exporter_scrape_duration_seconds 0.25
+# TYPE pressure_avg_10s_ratio gauge
+# UNIT pressure_avg_10s_ratio ratio
+# HELP pressure_avg_10s_ratio Ratio of time spent under pressure
+pressure_avg_10s_ratio{id="/"} NaN
+# EOF
+"#
+        );
+    }
+}
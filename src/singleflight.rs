@@ -0,0 +1,137 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Deduplicates concurrent calls, so that callers arriving while a call is
+/// in flight wait for its result instead of making their own call.
+pub struct Group<T> {
+    inflight: Mutex<Option<Arc<Call<T>>>>,
+}
+
+struct Call<T> {
+    state: Mutex<State<T>>,
+    done: Condvar,
+}
+
+enum State<T> {
+    Running,
+    Done(Arc<T>),
+    /// Leader panicked before producing a result
+    Abandoned,
+}
+
+/// Ends the call of the leader even if it panics, so that waiters are not
+/// left waiting forever.
+struct Leader<'a, T> {
+    group: &'a Group<T>,
+    call: Arc<Call<T>>,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        *self.group.inflight.lock().unwrap() = None;
+
+        let mut state = self.call.state.lock().unwrap();
+
+        if let State::Running = *state {
+            *state = State::Abandoned;
+        }
+
+        self.call.done.notify_all();
+    }
+}
+
+impl<T> Group<T> {
+    pub fn new() -> Self {
+        Group {
+            inflight: Mutex::new(None),
+        }
+    }
+
+    /// Calls `f` unless a call is in flight already. Waiters of a call that
+    /// panicked try again, the panic is only propagated to its caller.
+    pub fn run<F: FnOnce() -> T>(&self, f: F) -> Arc<T> {
+        loop {
+            let (call, leader) = {
+                let mut inflight = self.inflight.lock().unwrap();
+
+                match &*inflight {
+                    Some(call) => (call.clone(), false),
+                    None => {
+                        let call = Arc::new(Call {
+                            state: Mutex::new(State::Running),
+                            done: Condvar::new(),
+                        });
+
+                        *inflight = Some(call.clone());
+
+                        (call, true)
+                    }
+                }
+            };
+
+            if leader {
+                let leader = Leader { group: self, call };
+                let result = Arc::new(f());
+
+                *leader.call.state.lock().unwrap() = State::Done(result.clone());
+
+                return result;
+            }
+
+            let mut state = call.state.lock().unwrap();
+
+            while let State::Running = *state {
+                state = call.done.wait(state).unwrap();
+            }
+
+            if let State::Done(result) = &*state {
+                return result.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn waiters_retry_after_panic() {
+        let group = Arc::new(Group::new());
+        let (started, start) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let group = group.clone();
+
+            thread::spawn(move || {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    group.run(|| {
+                        started.send(()).unwrap();
+                        let _ = released.recv();
+                        panic!("collection failed");
+                    })
+                }))
+            })
+        };
+
+        start.recv().unwrap();
+
+        let waiter = {
+            let group = group.clone();
+            thread::spawn(move || group.run(|| 42))
+        };
+
+        // Let the waiter join the call in flight
+        thread::sleep(Duration::from_millis(50));
+        drop(release);
+
+        assert!(leader.join().unwrap().is_err());
+        assert_eq!(*waiter.join().unwrap(), 42);
+        assert_eq!(*group.run(|| 7), 7);
+    }
+}
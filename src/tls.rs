@@ -0,0 +1,307 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static BACKENDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections proxied at the same time, each taking two threads.
+const MAX_CONNECTIONS: usize = 128;
+
+/// Clients idle for this long are let go, as are those not taking responses.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connection accepted from a client, either over TCP or a unix socket.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Directory with the plaintext socket of a TLS listener, only accessible
+/// to the user running the exporter and removed on drop.
+pub struct Backend {
+    dir: PathBuf,
+}
+
+impl Backend {
+    fn create() -> io::Result<Self> {
+        loop {
+            let dir = env::temp_dir().join(format!(
+                "psi_exporter-{}-{}",
+                process::id(),
+                BACKENDS.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            // Creating fails if the directory exists, so that it can't be
+            // prepared by anyone else
+            match fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => return Ok(Backend { dir }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.join("backend.sock")
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Place of a proxied connection among `MAX_CONNECTIONS`, freed on drop.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        if active.fetch_add(1, Ordering::Relaxed) < MAX_CONNECTIONS {
+            Some(Slot(active.clone()))
+        } else {
+            active.fetch_sub(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves HTTPS on accepted connections with `tiny_http`, which only knows
+/// how to do TLS without client certificates.
+///
+/// TLS is terminated here instead, and plaintext is passed to the server
+/// listening on a unix socket in a private directory. Accepting stops once
+/// the listener is shut down.
+pub fn serve<S, I>(
+    incoming: I,
+    config: Arc<rustls::ServerConfig>,
+) -> io::Result<(tiny_http::Server, Backend)>
+where
+    S: Stream,
+    I: Iterator<Item = io::Result<S>> + Send + 'static,
+{
+    let backend = Backend::create()?;
+    let socket = backend.socket();
+
+    let server = tiny_http::Server::from_listener(UnixListener::bind(&socket)?, None)
+        .map_err(io::Error::other)?;
+
+    let active = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || {
+        for stream in incoming {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Listener is not listening anymore after shutdown(2)
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => break,
+                Err(e) => {
+                    log::warn!(err:% = e; "Error accepting connection");
+                    continue;
+                }
+            };
+
+            let slot = match Slot::take(&active) {
+                Some(slot) => slot,
+                None => {
+                    log::warn!("Too many TLS connections, closing a new one");
+                    continue;
+                }
+            };
+
+            let config = config.clone();
+            let socket = socket.clone();
+
+            thread::spawn(move || {
+                let _slot = slot;
+
+                if let Err(e) = proxy(stream, config, &socket) {
+                    log::warn!(err:% = e; "Error serving TLS connection");
+                }
+            });
+        }
+    });
+
+    Ok((server, backend))
+}
+
+/// Passes data between TLS client and plaintext backend until both are done.
+fn proxy<S: Stream>(
+    client: S,
+    config: Arc<rustls::ServerConfig>,
+    backend: &Path,
+) -> io::Result<()> {
+    client.set_read_timeout(Some(TIMEOUT))?;
+    client.set_write_timeout(Some(TIMEOUT))?;
+
+    let tls = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+    let upstream = UnixStream::connect(backend)?;
+
+    // Both directions need to write to the client when they touch the state
+    let shared = Arc::new(Mutex::new((tls, client.try_clone()?)));
+
+    let responses = {
+        let shared = shared.clone();
+        let upstream = upstream.try_clone()?;
+
+        thread::spawn(move || forward_responses(upstream, &shared))
+    };
+
+    let result = forward_requests(client, &upstream, &shared);
+
+    // Let the backend finish the connection, which in turn stops responses
+    let _ = upstream.shutdown(Shutdown::Write);
+
+    result.and(responses.join().unwrap())
+}
+
+fn forward_requests<S: Stream>(
+    mut client: S,
+    mut upstream: &UnixStream,
+    shared: &Mutex<(rustls::ServerConnection, S)>,
+) -> io::Result<()> {
+    let mut buf = [0; 16 * 1024];
+
+    loop {
+        let n = match client.read(&mut buf) {
+            Ok(n) => n,
+            // Idle clients are let go like those closing the connection,
+            // responses in flight still get through
+            Err(e) if is_timeout(&e) => 0,
+            Err(e) => return Err(e),
+        };
+
+        if n == 0 {
+            return Ok(());
+        }
+
+        let mut plaintext = vec![];
+        let mut closed = false;
+
+        {
+            let mut guard = shared.lock().unwrap();
+            let (tls, writer) = &mut *guard;
+
+            let mut incoming = &buf[..n];
+
+            while !incoming.is_empty() {
+                tls.read_tls(&mut incoming)?;
+
+                let state = tls.process_new_packets();
+
+                // Alerts for the client are queued even if processing failed
+                write_tls(tls, writer)?;
+
+                let state = state.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                let mut remaining = state.plaintext_bytes_to_read();
+                plaintext.resize(plaintext.len() + remaining, 0);
+
+                while remaining > 0 {
+                    let start = plaintext.len() - remaining;
+                    remaining -= tls.reader().read(&mut plaintext[start..])?;
+                }
+
+                closed |= state.peer_has_closed();
+            }
+        }
+
+        // Writing to the backend happens without the lock, so that
+        // responses can get through while the backend is busy
+        upstream.write_all(&plaintext)?;
+
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+fn forward_responses<S: Stream>(
+    mut upstream: UnixStream,
+    shared: &Mutex<(rustls::ServerConnection, S)>,
+) -> io::Result<()> {
+    let mut buf = [0; 16 * 1024];
+
+    loop {
+        let n = upstream.read(&mut buf)?;
+
+        let mut guard = shared.lock().unwrap();
+        let (tls, writer) = &mut *guard;
+
+        if n == 0 {
+            // The client might be gone already, nothing to report then
+            tls.send_close_notify();
+            let _ = write_tls(tls, writer);
+            let _ = writer.shutdown(Shutdown::Write);
+
+            return Ok(());
+        }
+
+        tls.writer().write_all(&buf[..n])?;
+        write_tls(tls, writer)?;
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn write_tls<S: Stream>(tls: &mut rustls::ServerConnection, writer: &mut S) -> io::Result<()> {
+    while tls.wants_write() {
+        tls.write_tls(writer)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use crate::collector::Metrics;
+use crate::kubernetes;
+use crate::{CgroupOptions, Measurements, PsiMeasurements, PsiStats, SYSTEM_ID};
+
+const MOUNTPOINT: &str = "/sys/fs/cgroup";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const PRESSURE_SUFFIX: &str = ".pressure";
+
+// Not a controller, but a knob to enable or disable PSI for a cgroup
+const PRESSURE_KNOB: &str = "cgroup.pressure";
+
+const BOOT_TIME_FILE: &str = "/proc/stat";
+
+macro_rules! skip_fail {
+    ($res:expr, $metrics:expr, $path:expr) => {
+        match $res {
+            Ok(val) => val,
+            Err(e) => {
+                let error = ReadError::from(e);
+
+                log::warn!(
+                    path:% = error.path().unwrap_or($path).display(),
+                    reason = error.reason(),
+                    err:% = error;
+                    "Skipping pressure file"
+                );
+
+                $metrics
+                    .read_errors
+                    .with_label_values(&[error.reason()])
+                    .inc();
+
+                continue;
+            }
+        }
+    };
+}
+
+pub(crate) fn get_service_measurements(cgroups: &CgroupOptions, metrics: &Metrics) -> Measurements {
+    let mut services: HashMap<_, PsiMeasurements> = HashMap::new();
+    let mut scanned = 0;
+
+    // Cgroups that got each relabeled id, and those that didn't get it
+    let mut relabeled: HashMap<String, String> = HashMap::new();
+    let mut collided = HashSet::new();
+
+    for root in &cgroups.roots {
+        let mut walker = walkdir::WalkDir::new(root);
+
+        // Pressure files of a cgroup are one level deeper than the cgroup itself
+        if let Some(max_depth) = cgroups.max_depth {
+            walker = walker.max_depth(max_depth + 1);
+        }
+
+        for entry in walker.into_iter().filter_entry(|e| {
+            !is_too_deep(cgroups, e)
+                && is_interesting(cgroups, root, e)
+                && !is_excluded(cgroups, root, e)
+        }) {
+            let entry = skip_fail!(entry, metrics, root);
+
+            if entry.file_type().is_dir() {
+                scanned += 1;
+
+                if log::log_enabled!(log::Level::Debug) {
+                    if let Some(id) = cgroup_id(cgroups, root, entry.path()) {
+                        if !cgroups.is_included(&id) {
+                            log::debug!(id = id.as_str(); "Skipping cgroup not included");
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            if !is_pressure(&entry) {
+                continue;
+            }
+
+            let path = entry.path();
+
+            let dir_name = skip_fail!(
+                path.parent()
+                    .and_then(|dir| cgroup_id(cgroups, root, dir))
+                    .ok_or(ReadError::Path),
+                metrics,
+                path
+            );
+
+            if !cgroups.is_included(&dir_name) {
+                continue;
+            }
+
+            let mut controller = skip_fail!(
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.to_string())
+                    .ok_or(ReadError::Path),
+                metrics,
+                path
+            );
+
+            controller.truncate(controller.len() - PRESSURE_SUFFIX.len());
+
+            // Relabeling may drop parts of the path kubernetes labels come from
+            let pod = if cgroups.kubernetes {
+                kubernetes::parse(&dir_name)
+            } else {
+                None
+            };
+
+            let id = cgroups.relabel(dir_name.clone());
+            let owner = relabeled
+                .entry(id.clone())
+                .or_insert_with(|| dir_name.clone());
+
+            // Merging would mix up pressure of different cgroups, so the
+            // first cgroup keeps the id
+            if *owner != dir_name {
+                if collided.insert(dir_name.clone()) {
+                    log::warn!(
+                        cgroup = dir_name.as_str(),
+                        id = id.as_str(),
+                        owner = owner.as_str();
+                        "Skipping cgroup relabeled to the id of another one"
+                    );
+                }
+
+                metrics
+                    .read_errors
+                    .with_label_values(&[ReadError::Relabel.reason()])
+                    .inc();
+                continue;
+            }
+
+            let dir_name = id;
+
+            let stats = skip_fail!(read_pressure(path), metrics, path);
+
+            metrics.files_read.inc();
+
+            let measurements = services.entry(dir_name).or_default();
+            measurements.kubernetes = pod;
+
+            populate_measurements(&controller, measurements, stats);
+        }
+    }
+
+    metrics.cgroups_scanned.set(scanned);
+
+    services
+}
+
+fn cgroup_id(cgroups: &CgroupOptions, root: &Path, dir: &Path) -> Option<String> {
+    // Same cgroups would have the same ids in every root, so with several
+    // roots these are told apart by the path of the root
+    let base = if cgroups.roots.len() > 1 {
+        root
+    } else {
+        Path::new("/")
+    };
+
+    base.join(dir.strip_prefix(root).ok()?)
+        .to_str()
+        .map(|s| s.to_string())
+}
+
+pub(crate) fn get_system_measurements(dir: &Path, metrics: &Metrics) -> Measurements {
+    let mut measurements = PsiMeasurements::default();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+
+    for entry in entries {
+        let entry = skip_fail!(entry, metrics, dir);
+        let path = entry.path();
+
+        let controller = skip_fail!(
+            entry.file_name().into_string().map_err(|_| ReadError::Path),
+            metrics,
+            &path
+        );
+
+        let stats = skip_fail!(read_pressure(&path), metrics, &path);
+
+        metrics.files_read.inc();
+
+        populate_measurements(&controller, &mut measurements, stats);
+    }
+
+    // Files in procfs don't keep their creation time, but system-wide
+    // pressure is accumulated since boot
+    let boot_time = read_boot_time();
+
+    for stats in measurements.controllers.values_mut() {
+        stats.created = boot_time;
+    }
+
+    if !measurements.controllers.is_empty() {
+        maplit::hashmap! { SYSTEM_ID.to_string() => measurements }
+    } else {
+        HashMap::new()
+    }
+}
+
+fn read_pressure(path: &Path) -> Result<PsiStats, ReadError> {
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    let mut buf = String::with_capacity(256);
+    file.read_to_string(&mut buf)?;
+
+    // Pressure files in cgroupfs are created along with their cgroup
+    // and never modified afterwards
+    let created = file.metadata().and_then(|m| m.modified()).ok();
+
+    let mut some = None;
+    let mut full = None;
+
+    for line in buf.lines() {
+        let parsed: psi::Psi = line
+            .parse()
+            .map_err(|_| ReadError::Parse(line.to_string()))?;
+
+        match parsed.line {
+            psi::PsiLine::Some => some = Some(parsed),
+            psi::PsiLine::Full => full = Some(parsed),
+        };
+    }
+
+    Ok(PsiStats {
+        some,
+        full,
+        created,
+    })
+}
+
+fn read_boot_time() -> Option<time::SystemTime> {
+    fs::read_to_string(BOOT_TIME_FILE)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+        .map(|btime| time::UNIX_EPOCH + time::Duration::from_secs(btime))
+}
+
+/// Returns the first `cgroup2` mount point, falling back to `/sys/fs/cgroup`.
+pub fn detect_cgroup_root() -> PathBuf {
+    fs::read_to_string(MOUNTINFO)
+        .ok()
+        .and_then(|mountinfo| find_cgroup2_mount(&mountinfo))
+        .unwrap_or_else(|| PathBuf::from(MOUNTPOINT))
+}
+
+fn find_cgroup2_mount(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        let mut sides = line.splitn(2, " - ");
+
+        let mount_point = sides.next()?.split(' ').nth(4)?;
+        let fs_type = sides.next()?.split(' ').next()?;
+
+        if fs_type == "cgroup2" {
+            Some(PathBuf::from(unescape_mountinfo(mount_point)))
+        } else {
+            None
+        }
+    })
+}
+
+fn unescape_mountinfo(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+fn populate_measurements(
+    controller: &str,
+    measurements: &mut PsiMeasurements,
+    measurement: PsiStats,
+) {
+    measurements
+        .controllers
+        .insert(controller.to_string(), measurement);
+}
+
+fn is_interesting(cgroups: &CgroupOptions, root: &Path, entry: &walkdir::DirEntry) -> bool {
+    let name = match entry.file_name().to_str() {
+        Some(name) => name,
+        None => return false,
+    };
+
+    // Containers of pods run in scopes with the systemd cgroup driver
+    if name.ends_with(".scope") && cgroups.kubernetes {
+        return cgroup_id(cgroups, root, entry.path())
+            .and_then(|id| kubernetes::parse(&id))
+            .map(|cgroup| !cgroup.container_id.is_empty())
+            .unwrap_or(false);
+    }
+
+    !(name.ends_with(".mount") || name.ends_with(".socket") || name.ends_with(".scope"))
+}
+
+fn is_excluded(cgroups: &CgroupOptions, root: &Path, entry: &walkdir::DirEntry) -> bool {
+    if !entry.file_type().is_dir() {
+        return false;
+    }
+
+    let excluded = cgroup_id(cgroups, root, entry.path())
+        .map(|id| cgroups.is_excluded(&id))
+        .unwrap_or(false);
+
+    if excluded {
+        log::debug!(path:% = entry.path().display(); "Skipping excluded cgroup");
+    }
+
+    excluded
+}
+
+/// Directories one level below the maximum depth are only walked for
+/// pressure files of their parents.
+fn is_too_deep(cgroups: &CgroupOptions, entry: &walkdir::DirEntry) -> bool {
+    entry.file_type().is_dir()
+        && cgroups
+            .max_depth
+            .map(|max_depth| entry.depth() > max_depth)
+            .unwrap_or(false)
+}
+
+fn is_pressure(entry: &walkdir::DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|s| s.ends_with(PRESSURE_SUFFIX) && s != PRESSURE_KNOB)
+        .unwrap_or(false)
+}
+
+#[derive(Debug)]
+enum ReadError {
+    Walk(walkdir::Error),
+    Path,
+    Io(std::io::Error),
+    Parse(String),
+    Relabel,
+}
+
+impl ReadError {
+    fn reason(&self) -> &'static str {
+        match self {
+            ReadError::Walk(_) => "walk",
+            ReadError::Path => "path",
+            ReadError::Io(_) => "io",
+            ReadError::Parse(_) => "parse",
+            ReadError::Relabel => "relabel",
+        }
+    }
+
+    /// Path that failed, if more specific than the one being read.
+    fn path(&self) -> Option<&Path> {
+        match self {
+            ReadError::Walk(e) => e.path(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReadError::Walk(e) => write!(f, "{}", e),
+            ReadError::Path => write!(f, "unexpected path"),
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Parse(line) => write!(f, "unexpected line {:?}", line),
+            ReadError::Relabel => write!(f, "relabeled to the id of another cgroup"),
+        }
+    }
+}
+
+impl From<walkdir::Error> for ReadError {
+    fn from(e: walkdir::Error) -> Self {
+        ReadError::Walk(e)
+    }
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgroup_options(relabel: &[(&str, &str)]) -> CgroupOptions {
+        CgroupOptions {
+            roots: vec![],
+            include: None,
+            exclude: None,
+            max_depth: None,
+            relabel: relabel
+                .iter()
+                .map(|(regex, replacement)| {
+                    let regex = regex::Regex::new(&format!("^(?:{})$", regex)).unwrap();
+                    (regex, replacement.to_string())
+                })
+                .collect(),
+            kubernetes: false,
+            kubelet: None,
+        }
+    }
+
+    #[test]
+    fn relabel() {
+        let cgroups = cgroup_options(&[
+            (r"/system\.slice/(.*)\.service", "service/$1"),
+            (r"/system\.slice/.*", "system"),
+        ]);
+
+        let relabel = |id: &str| cgroups.relabel(id.to_string());
+
+        // The first matching rule wins
+        assert_eq!(relabel("/system.slice/sshd.service"), "service/sshd");
+        assert_eq!(relabel("/system.slice/sshd.socket"), "system");
+
+        // Rules are anchored
+        assert_eq!(
+            relabel("/user.slice/system.slice/a.service"),
+            "/user.slice/system.slice/a.service"
+        );
+        assert_eq!(relabel("/system.slice"), "/system.slice");
+    }
+
+    #[test]
+    fn relabel_collision() {
+        let root =
+            std::env::temp_dir().join(format!("psi_exporter-relabel-{}", std::process::id()));
+
+        for service in &["a.service", "b.service"] {
+            let dir = root.join("system.slice").join(service);
+            fs::create_dir_all(&dir).unwrap();
+
+            for controller in &["cpu", "io"] {
+                fs::write(
+                    dir.join(format!("{}.pressure", controller)),
+                    "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+                )
+                .unwrap();
+            }
+        }
+
+        let mut cgroups = cgroup_options(&[(r"/system\.slice/.*\.service", "services")]);
+        cgroups.roots = vec![root.clone()];
+
+        let metrics = Metrics::new();
+        let services = get_service_measurements(&cgroups, &metrics);
+
+        fs::remove_dir_all(&root).unwrap();
+
+        // Pressure of one of the services is kept, the other one is skipped
+        assert_eq!(services["services"].controllers.len(), 2);
+        assert_eq!(metrics.files_read.get(), 2);
+        assert_eq!(metrics.read_errors.with_label_values(&["relabel"]).get(), 2);
+    }
+
+    #[test]
+    fn cgroup2_mount() {
+        let mountinfo = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                         30 22 0:26 / /host\\040root/sys/fs/cgroup rw,nosuid shared:9 - \
+                         cgroup2 cgroup2 rw,nsdelegate\n\
+                         31 22 0:27 / /sys/fs/cgroup rw - cgroup2 cgroup2 rw\n";
+
+        // The first mount wins, with spaces escaped in mountinfo
+        assert_eq!(
+            find_cgroup2_mount(mountinfo),
+            Some(PathBuf::from("/host root/sys/fs/cgroup"))
+        );
+
+        assert_eq!(
+            find_cgroup2_mount("22 1 8:1 / / rw - ext4 /dev/sda1 rw\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn mountinfo_unescaping() {
+        assert_eq!(unescape_mountinfo("/a\\011b\\012c"), "/a\tb\nc");
+
+        // Backslashes are unescaped last, so they don't start new escapes
+        assert_eq!(unescape_mountinfo("/a\\134040b"), "/a\\040b");
+    }
+}
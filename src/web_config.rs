@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+/// Web configuration following the conventions of `web-config.yml` from
+/// Prometheus exporter-toolkit.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    #[serde(default)]
+    tls_server_config: Option<TlsServerConfig>,
+    #[serde(default)]
+    basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsServerConfig {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    #[serde(default)]
+    client_auth_type: ClientAuthType,
+    #[serde(default)]
+    client_ca_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+impl WebConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+        let config: WebConfig = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("error parsing {}: {}", path.display(), e))?;
+
+        if let Some(tls) = &config.tls_server_config {
+            match (&tls.client_auth_type, &tls.client_ca_file) {
+                (ClientAuthType::NoClientCert, Some(_)) => {
+                    return Err("client_ca_file is set without client_auth_type".to_string())
+                }
+                (ClientAuthType::NoClientCert, None) | (_, Some(_)) => {}
+                (_, None) => {
+                    return Err("client_auth_type requires client_ca_file".to_string());
+                }
+            }
+
+            // Catch unreadable certificates and keys at startup
+            config.tls_config()?;
+        }
+
+        for (user, hash) in &config.basic_auth_users {
+            bcrypt::verify("", hash)
+                .map_err(|e| format!("invalid password hash for user {}: {}", user, e))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Returns TLS configuration for the server, if TLS is enabled.
+    pub fn tls_config(&self) -> Result<Option<Arc<rustls::ServerConfig>>, String> {
+        let tls = match &self.tls_server_config {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+
+        let certs = load_certs(&tls.cert_file)?;
+
+        let key = rustls_pemfile::private_key(&mut io::BufReader::new(open(&tls.key_file)?))
+            .map_err(|e| format!("error reading {}: {}", tls.key_file.display(), e))?
+            .ok_or_else(|| format!("no private key found in {}", tls.key_file.display()))?;
+
+        let builder = rustls::ServerConfig::builder();
+
+        let builder = match &tls.client_ca_file {
+            None => builder.with_no_client_auth(),
+            Some(client_ca_file) => {
+                let mut roots = rustls::RootCertStore::empty();
+
+                for cert in load_certs(client_ca_file)? {
+                    roots.add(cert).map_err(|e| {
+                        format!("invalid certificate in {}: {}", client_ca_file.display(), e)
+                    })?;
+                }
+
+                let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots));
+
+                let verifier = if tls.client_auth_type == ClientAuthType::VerifyClientCertIfGiven {
+                    verifier.allow_unauthenticated()
+                } else {
+                    verifier
+                };
+
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+            }
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| e.to_string())?;
+
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Some(Arc::new(config)))
+    }
+
+    /// Checks the value of `Authorization` header against configured users,
+    /// any request is authorized if there are none.
+    pub fn is_authorized(&self, authorization: &str) -> bool {
+        if self.basic_auth_users.is_empty() {
+            return true;
+        }
+
+        let credentials = match authorization.strip_prefix("Basic ") {
+            Some(encoded) => decode_credentials(encoded.trim()),
+            None => None,
+        };
+
+        let (user, password) = match credentials {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+
+        self.basic_auth_users
+            .get(&user)
+            .map(|hash| bcrypt::verify(&password, hash).unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+fn decode_credentials(encoded: &str) -> Option<(String, String)> {
+    use base64::Engine;
+
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+fn open(path: &Path) -> Result<fs::File, String> {
+    fs::File::open(path).map_err(|e| format!("error opening {}: {}", path.display(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, String> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+
+    Ok(certs)
+}
@@ -0,0 +1,695 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use prometheus::Encoder;
+
+mod common;
+
+use common::BUSY;
+
+const ZERO: &str = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n\
+                    full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+
+/// Temporary cgroup tree with pressure files.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = common::temp_dir(name);
+        fs::create_dir_all(dir.join("cgroup")).unwrap();
+
+        Fixture { dir }
+    }
+
+    fn write(self, path: &str, contents: &str) -> Self {
+        let path = self.dir.join("cgroup").join(path);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+
+        self
+    }
+
+    /// Writes a file of system-wide pressure, like `/proc/pressure/cpu`.
+    fn write_system(self, controller: &str, contents: &str) -> Self {
+        let dir = self.dir.join("pressure");
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(controller), contents).unwrap();
+
+        self
+    }
+
+    fn options(&self) -> psi_exporter::Options {
+        psi_exporter::Options {
+            cgroups: psi_exporter::CgroupOptions {
+                roots: vec![self.dir.join("cgroup")],
+                include: None,
+                exclude: None,
+                max_depth: None,
+                relabel: vec![],
+                kubernetes: false,
+                kubelet: None,
+            },
+            system_pressure: None,
+            report_avg: true,
+            report_zeros: true,
+        }
+    }
+
+    fn expose(&self, options: psi_exporter::Options) -> String {
+        encode(&psi_exporter::Collector::new(options))
+    }
+}
+
+/// Returns text exposition, without metrics that change between runs.
+fn encode(collector: &psi_exporter::Collector) -> String {
+    let families: Vec<_> = collector
+        .families(&collector.measurements())
+        .into_iter()
+        .filter(|family| {
+            family.get_name() != "psi_exporter_scrape_duration_seconds"
+                && family.get_name() != "psi_exporter_last_collection_timestamp_seconds"
+        })
+        .collect();
+
+    let mut buffer = vec![];
+    prometheus::TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .unwrap();
+
+    String::from_utf8(buffer).unwrap()
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn exposition() {
+    let fixture = Fixture::new("exposition")
+        .write("cgroup.pressure", "1\n")
+        .write("cpu.pressure", BUSY)
+        .write("system.slice/a.service/io.pressure", BUSY)
+        .write("system.slice/a.service/memory.pressure", ZERO);
+
+    assert_eq!(
+        fixture.expose(fixture.options()),
+        r#"# HELP pressure_avg_10s_ratio Ratio of time spent under pressure in the last 10s at time of measurement
+# TYPE pressure_avg_10s_ratio gauge
+pressure_avg_10s_ratio{controller="cpu",id="/",kind="full"} 0.025
+pressure_avg_10s_ratio{controller="cpu",id="/",kind="some"} 0.125
+pressure_avg_10s_ratio{controller="io",id="/system.slice/a.service",kind="full"} 0.025
+pressure_avg_10s_ratio{controller="io",id="/system.slice/a.service",kind="some"} 0.125
+pressure_avg_10s_ratio{controller="memory",id="/system.slice/a.service",kind="full"} 0
+pressure_avg_10s_ratio{controller="memory",id="/system.slice/a.service",kind="some"} 0
+# HELP pressure_avg_300s_ratio Ratio of time spent under pressure in the last 300s at time of measurement
+# TYPE pressure_avg_300s_ratio gauge
+pressure_avg_300s_ratio{controller="cpu",id="/",kind="full"} 0
+pressure_avg_300s_ratio{controller="cpu",id="/",kind="some"} 0.01
+pressure_avg_300s_ratio{controller="io",id="/system.slice/a.service",kind="full"} 0
+pressure_avg_300s_ratio{controller="io",id="/system.slice/a.service",kind="some"} 0.01
+pressure_avg_300s_ratio{controller="memory",id="/system.slice/a.service",kind="full"} 0
+pressure_avg_300s_ratio{controller="memory",id="/system.slice/a.service",kind="some"} 0
+# HELP pressure_avg_60s_ratio Ratio of time spent under pressure in the last 60s at time of measurement
+# TYPE pressure_avg_60s_ratio gauge
+pressure_avg_60s_ratio{controller="cpu",id="/",kind="full"} 0.01
+pressure_avg_60s_ratio{controller="cpu",id="/",kind="some"} 0.05
+pressure_avg_60s_ratio{controller="io",id="/system.slice/a.service",kind="full"} 0.01
+pressure_avg_60s_ratio{controller="io",id="/system.slice/a.service",kind="some"} 0.05
+pressure_avg_60s_ratio{controller="memory",id="/system.slice/a.service",kind="full"} 0
+pressure_avg_60s_ratio{controller="memory",id="/system.slice/a.service",kind="some"} 0
+# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/",kind="some"} 1.5
+pressure_total_seconds{controller="io",id="/system.slice/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="io",id="/system.slice/a.service",kind="some"} 1.5
+pressure_total_seconds{controller="memory",id="/system.slice/a.service",kind="full"} 0
+pressure_total_seconds{controller="memory",id="/system.slice/a.service",kind="some"} 0
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 3
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 3
+"#
+    );
+}
+
+#[test]
+fn uninteresting_units_are_pruned() {
+    let fixture = Fixture::new("pruning")
+        .write("system.slice/a.service/cpu.pressure", BUSY)
+        .write("system.slice/a.service/b.scope/cpu.pressure", BUSY)
+        .write("system.slice/c.mount/cpu.pressure", BUSY)
+        .write("system.slice/d.socket/cpu.pressure", BUSY)
+        .write("system.slice/d.socket/e.service/cpu.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 3
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn several_roots() {
+    let fixture = Fixture::new("several_roots")
+        .write("host/system.slice/a.service/cpu.pressure", BUSY)
+        .write("guest/system.slice/a.service/cpu.pressure", ZERO);
+
+    let host = fixture.dir.join("cgroup/host");
+    let guest = fixture.dir.join("cgroup/guest");
+
+    let mut options = fixture.options();
+    options.cgroups.roots = vec![host.clone(), guest.clone()];
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        format!(
+            r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{{controller="cpu",id="{guest}/system.slice/a.service",kind="full"}} 0
+pressure_total_seconds{{controller="cpu",id="{guest}/system.slice/a.service",kind="some"}} 0
+pressure_total_seconds{{controller="cpu",id="{host}/system.slice/a.service",kind="full"}} 0.25
+pressure_total_seconds{{controller="cpu",id="{host}/system.slice/a.service",kind="some"}} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 6
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 2
+"#,
+            host = host.display(),
+            guest = guest.display(),
+        )
+    );
+}
+
+#[test]
+fn include() {
+    let fixture = Fixture::new("include")
+        .write("cpu.pressure", BUSY)
+        .write("system.slice/cpu.pressure", BUSY)
+        .write("system.slice/a.service/cpu.pressure", BUSY)
+        .write("user.slice/cpu.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.cgroups.include = Some(regex::RegexSet::new([r"^(?:/system\.slice/.*)$"]).unwrap());
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 4
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn exclude() {
+    let fixture = Fixture::new("exclude")
+        .write("system.slice/a.service/cpu.pressure", BUSY)
+        .write("user.slice/cpu.pressure", BUSY)
+        .write("user.slice/user-1000.slice/cpu.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.cgroups.exclude = Some(regex::RegexSet::new([r"^(?:/user\.slice)$"]).unwrap());
+    options.report_avg = false;
+
+    // Children of excluded cgroups are not walked at all
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/system.slice/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 3
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn max_depth() {
+    let fixture = Fixture::new("max_depth")
+        .write("system.slice/cpu.pressure", BUSY)
+        .write("system.slice/a.service/cpu.pressure", BUSY)
+        .write("system.slice/a.service/b.service/cpu.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.cgroups.max_depth = Some(1);
+    options.report_avg = false;
+
+    // Cgroups below the maximum depth are neither exported nor counted
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/system.slice",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/system.slice",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 2
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn silence_zeros() {
+    let fixture = Fixture::new("silence_zeros")
+        .write("a.service/cpu.pressure", BUSY)
+        .write("b.service/cpu.pressure", ZERO);
+
+    let mut options = fixture.options();
+    options.report_zeros = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_avg_10s_ratio Ratio of time spent under pressure in the last 10s at time of measurement
+# TYPE pressure_avg_10s_ratio gauge
+pressure_avg_10s_ratio{controller="cpu",id="/a.service",kind="full"} 0.025
+pressure_avg_10s_ratio{controller="cpu",id="/a.service",kind="some"} 0.125
+# HELP pressure_avg_300s_ratio Ratio of time spent under pressure in the last 300s at time of measurement
+# TYPE pressure_avg_300s_ratio gauge
+pressure_avg_300s_ratio{controller="cpu",id="/a.service",kind="some"} 0.01
+# HELP pressure_avg_60s_ratio Ratio of time spent under pressure in the last 60s at time of measurement
+# TYPE pressure_avg_60s_ratio gauge
+pressure_avg_60s_ratio{controller="cpu",id="/a.service",kind="full"} 0.01
+pressure_avg_60s_ratio{controller="cpu",id="/a.service",kind="some"} 0.05
+# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 3
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 2
+"#
+    );
+}
+
+#[test]
+fn disable_avg() {
+    let fixture = Fixture::new("disable_avg").write("a.service/memory.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="memory",id="/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="memory",id="/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 2
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn missing_full() {
+    // Only memory and io pressure had full lines before Linux 5.13
+    let fixture = Fixture::new("missing_full").write(
+        "a.service/cpu.pressure",
+        "some avg10=12.50 avg60=5.00 avg300=1.00 total=1500000\n",
+    );
+
+    let mut options = fixture.options();
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 2
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
+
+#[test]
+fn malformed_files() {
+    let fixture = Fixture::new("malformed")
+        .write("a.service/cpu.pressure", BUSY)
+        .write("a.service/io.pressure", "some avg10=garbage\n")
+        .write("b.service/memory.pressure", "\u{0}\u{0}\u{0}\n");
+
+    let mut options = fixture.options();
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="/a.service",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="/a.service",kind="some"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 3
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+# HELP psi_exporter_read_errors_total Number of pressure files that could not be read, by reason
+# TYPE psi_exporter_read_errors_total counter
+psi_exporter_read_errors_total{reason="parse"} 2
+"#
+    );
+}
+
+#[test]
+fn system_pressure() {
+    // Interrupt pressure from Linux 6.1 only has a full line
+    let fixture = Fixture::new("system_pressure")
+        .write_system("cpu", BUSY)
+        .write_system(
+            "irq",
+            "full avg10=2.50 avg60=1.00 avg300=0.00 total=250000\n",
+        );
+
+    let mut options = fixture.options();
+    options.system_pressure = Some(fixture.dir.join("pressure"));
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{controller="cpu",id="system",kind="full"} 0.25
+pressure_total_seconds{controller="cpu",id="system",kind="some"} 1.5
+pressure_total_seconds{controller="irq",id="system",kind="full"} 0.25
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 1
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 2
+"#
+    );
+
+    let mut options = fixture.options();
+    options.system_pressure = Some(fixture.dir.join("pressure"));
+
+    let measurements = psi_exporter::Collector::new(options).measurements();
+    let system = &measurements[psi_exporter::SYSTEM_ID];
+
+    // System-wide pressure is accumulated since boot
+    let created: Vec<_> = system
+        .controllers
+        .values()
+        .map(|stats| stats.created.unwrap())
+        .collect();
+
+    assert_eq!(created.len(), 2);
+    assert_eq!(created[0], created[1]);
+    assert!(created[0] < time::SystemTime::now());
+}
+
+#[test]
+fn kubernetes() {
+    let fixture = Fixture::new("kubernetes")
+        .write("kubepods.slice/kubepods-burstable.slice/cpu.pressure", BUSY)
+        .write(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope/cpu.pressure",
+            BUSY,
+        )
+        .write(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/crio-conmon-1a2b3c.scope/cpu.pressure",
+            BUSY,
+        )
+        .write(
+            "kubepods.slice/kubepods-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice/cpu.pressure",
+            BUSY,
+        )
+        .write("kubepods/besteffort/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345/4d5e6f/cpu.pressure", BUSY)
+        .write("system.slice/a.service/cpu.pressure", BUSY)
+        .write("system.slice/session-1.scope/cpu.pressure", BUSY);
+
+    let mut options = fixture.options();
+    options.cgroups.kubernetes = true;
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice",kind="full",pod_uid="",qos_class="burstable"} 0.25
+pressure_total_seconds{container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice",kind="some",pod_uid="",qos_class="burstable"} 1.5
+pressure_total_seconds{container_id="",controller="cpu",id="/kubepods.slice/kubepods-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice",kind="full",pod_uid="5e6f7a8b-0000-4c4c-8d8d-123456789abc",qos_class="guaranteed"} 0.25
+pressure_total_seconds{container_id="",controller="cpu",id="/kubepods.slice/kubepods-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice",kind="some",pod_uid="5e6f7a8b-0000-4c4c-8d8d-123456789abc",qos_class="guaranteed"} 1.5
+pressure_total_seconds{container_id="",controller="cpu",id="/system.slice/a.service",kind="full",pod_uid="",qos_class=""} 0.25
+pressure_total_seconds{container_id="",controller="cpu",id="/system.slice/a.service",kind="some",pod_uid="",qos_class=""} 1.5
+pressure_total_seconds{container_id="1a2b3c",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope",kind="full",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 0.25
+pressure_total_seconds{container_id="1a2b3c",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope",kind="some",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 1.5
+pressure_total_seconds{container_id="4d5e6f",controller="cpu",id="/kubepods/besteffort/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345/4d5e6f",kind="full",pod_uid="9d8c7b6a-1111-4e4e-a0a0-abcdef012345",qos_class="besteffort"} 0.25
+pressure_total_seconds{container_id="4d5e6f",controller="cpu",id="/kubepods/besteffort/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345/4d5e6f",kind="some",pod_uid="9d8c7b6a-1111-4e4e-a0a0-abcdef012345",qos_class="besteffort"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 12
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 5
+"#
+    );
+}
+
+/// Stand-in for kubelet serving `/pods`, returns its url along with
+/// `Authorization` headers of requests received.
+fn kubelet(pods: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+
+    let requests = Arc::new(Mutex::new(vec![]));
+    let received = requests.clone();
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            assert_eq!(request.url(), "/pods");
+
+            let authorization = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Authorization"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+
+            received.lock().unwrap().push(authorization);
+
+            let _ = request.respond(tiny_http::Response::from_string(pods));
+        }
+    });
+
+    (url, requests)
+}
+
+fn endpoint(url: String) -> psi_exporter::kubelet::Endpoint {
+    psi_exporter::kubelet::Endpoint {
+        url,
+        token_file: None,
+        ca_file: None,
+        insecure_skip_verify: false,
+        timeout: time::Duration::from_secs(5),
+        refresh_interval: time::Duration::from_secs(3600),
+    }
+}
+
+const PODS: &str = r#"{
+  "kind": "PodList",
+  "apiVersion": "v1",
+  "items": [
+    {
+      "metadata": {"name": "web-0", "namespace": "shop", "uid": "0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f"},
+      "status": {
+        "containerStatuses": [
+          {"name": "nginx", "containerID": "containerd://1a2b3c"},
+          {"name": "pending"}
+        ],
+        "initContainerStatuses": [
+          {"name": "migrate", "containerID": "containerd://7d8e9f"}
+        ]
+      }
+    }
+  ]
+}"#;
+
+#[test]
+fn kubelet_names() {
+    let fixture = Fixture::new("kubelet_names")
+        .write("token", "secret\n")
+        .write(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cpu.pressure",
+            BUSY,
+        )
+        .write(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope/cpu.pressure",
+            BUSY,
+        )
+        .write(
+            "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-7d8e9f.scope/cpu.pressure",
+            BUSY,
+        )
+        .write("kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice/cpu.pressure", BUSY);
+
+    let (url, requests) = kubelet(PODS);
+
+    let mut endpoint = endpoint(url);
+    endpoint.token_file = Some(fixture.dir.join("cgroup/token"));
+
+    let resolver = psi_exporter::kubelet::Resolver::new(endpoint).unwrap();
+
+    let mut options = fixture.options();
+    options.cgroups.kubernetes = true;
+    options.cgroups.kubelet = Some(Arc::new(resolver));
+    options.report_avg = false;
+
+    let collector = psi_exporter::Collector::new(options);
+
+    let expected = r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice",kind="full",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 0.25
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice",kind="some",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 1.5
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice",kind="full",namespace="",pod="",pod_uid="5e6f7a8b-0000-4c4c-8d8d-123456789abc",qos_class="burstable"} 0.25
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod5e6f7a8b_0000_4c4c_8d8d_123456789abc.slice",kind="some",namespace="",pod="",pod_uid="5e6f7a8b-0000-4c4c-8d8d-123456789abc",qos_class="burstable"} 1.5
+pressure_total_seconds{container="migrate",container_id="7d8e9f",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-7d8e9f.scope",kind="full",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 0.25
+pressure_total_seconds{container="migrate",container_id="7d8e9f",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-7d8e9f.scope",kind="some",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 1.5
+pressure_total_seconds{container="nginx",container_id="1a2b3c",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope",kind="full",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 0.25
+pressure_total_seconds{container="nginx",container_id="1a2b3c",controller="cpu",id="/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-1a2b3c.scope",kind="some",namespace="shop",pod="web-0",pod_uid="0c6b5a1e-3f1d-4b4e-9f5a-7f0b1c2d3e4f",qos_class="burstable"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 7
+# HELP psi_exporter_kubelet_requests_total Number of requests to kubelet for names of pods, by result
+# TYPE psi_exporter_kubelet_requests_total counter
+psi_exporter_kubelet_requests_total{result="success"} 1
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 4
+"#;
+
+    assert_eq!(encode(&collector), expected);
+
+    // Unknown pod doesn't trigger requests until the refresh interval passes
+    collector.measurements();
+
+    assert_eq!(*requests.lock().unwrap(), vec!["Bearer secret".to_string()]);
+}
+
+#[test]
+fn kubelet_sandbox() {
+    let scope = "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0c6b5a1e_3f1d_4b4e_9f5a_7f0b1c2d3e4f.slice/cri-containerd-5a5a5a.scope";
+
+    // Sandbox of the pod is not among its containers
+    let fixture = Fixture::new("kubelet_sandbox").write(&format!("{}/cpu.pressure", scope), BUSY);
+
+    let (url, requests) = kubelet(PODS);
+
+    let mut endpoint = endpoint(url);
+    endpoint.refresh_interval = time::Duration::from_secs(0);
+
+    let mut options = fixture.options();
+    options.cgroups.kubernetes = true;
+    options.cgroups.kubelet = Some(Arc::new(
+        psi_exporter::kubelet::Resolver::new(endpoint).unwrap(),
+    ));
+
+    let collector = psi_exporter::Collector::new(options);
+
+    for _ in 0..3 {
+        let measurements = collector.measurements();
+        let cgroup = measurements[&format!("/{}", scope)]
+            .kubernetes
+            .as_ref()
+            .unwrap();
+
+        assert_eq!(cgroup.pod, "web-0");
+        assert_eq!(cgroup.container_id, "5a5a5a");
+        assert_eq!(cgroup.container, "");
+    }
+
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn kubelet_unavailable() {
+    let fixture = Fixture::new("kubelet_unavailable").write(
+        "kubepods/burstable/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345/cpu.pressure",
+        BUSY,
+    );
+
+    // Nothing listens on the port of a dropped stand-in
+    let url = {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        format!("http://{}", server.server_addr().to_ip().unwrap())
+    };
+
+    let mut options = fixture.options();
+    options.cgroups.kubernetes = true;
+    options.cgroups.kubelet = Some(Arc::new(
+        psi_exporter::kubelet::Resolver::new(endpoint(url)).unwrap(),
+    ));
+    options.report_avg = false;
+
+    assert_eq!(
+        fixture.expose(options),
+        r#"# HELP pressure_total_seconds Total time spent under pressure
+# TYPE pressure_total_seconds counter
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods/burstable/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345",kind="full",namespace="",pod="",pod_uid="9d8c7b6a-1111-4e4e-a0a0-abcdef012345",qos_class="burstable"} 0.25
+pressure_total_seconds{container="",container_id="",controller="cpu",id="/kubepods/burstable/pod9d8c7b6a-1111-4e4e-a0a0-abcdef012345",kind="some",namespace="",pod="",pod_uid="9d8c7b6a-1111-4e4e-a0a0-abcdef012345",qos_class="burstable"} 1.5
+# HELP psi_exporter_cgroups_scanned Number of cgroups scanned during the last collection
+# TYPE psi_exporter_cgroups_scanned gauge
+psi_exporter_cgroups_scanned 4
+# HELP psi_exporter_kubelet_requests_total Number of requests to kubelet for names of pods, by result
+# TYPE psi_exporter_kubelet_requests_total counter
+psi_exporter_kubelet_requests_total{result="error"} 1
+# HELP psi_exporter_pressure_files_read_total Number of pressure files successfully read
+# TYPE psi_exporter_pressure_files_read_total counter
+psi_exporter_pressure_files_read_total 1
+"#
+    );
+}
@@ -0,0 +1,67 @@
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use std::{env, fs, thread};
+
+/// Pressure of a cgroup that has been stalled for a while.
+pub const BUSY: &str = "some avg10=12.50 avg60=5.00 avg300=1.00 total=1500000\n\
+                        full avg10=2.50 avg60=1.00 avg300=0.00 total=250000\n";
+
+/// Creates an empty directory for a test, unique to the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("psi_exporter-{}-{}", name, std::process::id()));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Command running the exporter binary with its output discarded.
+pub fn command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_psi_exporter"));
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+    command
+}
+
+/// Exporter running for a test, killed on drop along with removal of its
+/// directory.
+pub struct Process {
+    pub child: Child,
+    pub dir: PathBuf,
+}
+
+impl Process {
+    /// Spawns the command and waits for the exporter to be `ready`.
+    pub fn spawn(mut command: Command, dir: PathBuf, ready: impl Fn() -> bool) -> Self {
+        let process = Process {
+            child: command.spawn().unwrap(),
+            dir,
+        };
+
+        for _ in 0..100 {
+            if ready() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        process
+    }
+
+    pub fn running(&mut self) -> bool {
+        self.child.try_wait().unwrap().is_none()
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+struct Exporter {
+    process: common::Process,
+}
+
+impl Exporter {
+    /// Starts the exporter listening on a unix socket, with enough cgroups
+    /// that a response doesn't fit into socket buffers.
+    fn start(name: &str, cgroups: usize) -> Self {
+        let dir = common::temp_dir(name);
+
+        for i in 0..cgroups {
+            let cgroup = dir.join(format!("cgroup/service-{}.service", i));
+            fs::create_dir_all(&cgroup).unwrap();
+
+            for controller in &["cpu", "io", "memory"] {
+                fs::write(
+                    cgroup.join(format!("{}.pressure", controller)),
+                    common::BUSY,
+                )
+                .unwrap();
+            }
+        }
+
+        let socket = dir.join("web.sock");
+        let mut command = common::command();
+
+        command
+            .arg("--web.listen-address")
+            .arg(format!("unix:{}", socket.display()))
+            .arg("--web.shutdown-timeout")
+            .arg("10s")
+            .arg("--cgroup.root")
+            .arg(dir.join("cgroup"));
+
+        let process = common::Process::spawn(command, dir, || UnixStream::connect(&socket).is_ok());
+
+        Exporter { process }
+    }
+
+    fn connect(&self) -> std::io::Result<UnixStream> {
+        UnixStream::connect(self.process.dir.join("web.sock"))
+    }
+}
+
+#[test]
+fn connections_are_refused_while_draining() {
+    let mut exporter = Exporter::start("shutdown", 500);
+
+    // Response is not read yet, so that the request stays in flight
+    let mut stream = exporter.connect().unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+
+    unsafe {
+        libc::kill(exporter.process.child.id() as libc::pid_t, libc::SIGTERM);
+    }
+
+    let mut refused = false;
+
+    for _ in 0..50 {
+        if exporter.connect().is_err() {
+            refused = true;
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    assert!(refused);
+    assert!(exporter.process.running());
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(exporter.process.child.wait().unwrap().success());
+}
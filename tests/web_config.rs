@@ -0,0 +1,257 @@
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+mod common;
+
+struct Pki {
+    ca: rcgen::Certificate,
+    ca_key: rcgen::KeyPair,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+
+        Pki {
+            ca: params.self_signed(&ca_key).unwrap(),
+            ca_key,
+        }
+    }
+
+    fn issue(&self, name: &str) -> (rcgen::Certificate, rcgen::KeyPair) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+        (cert, key)
+    }
+}
+
+struct Exporter {
+    process: common::Process,
+    port: u16,
+}
+
+impl Exporter {
+    fn start(name: &str, pki: &Pki, web_config: &str) -> Self {
+        let dir = common::temp_dir(name);
+        fs::create_dir_all(dir.join("cgroup")).unwrap();
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+
+        let (cert, key) = pki.issue("localhost");
+
+        fs::write(dir.join("ca.crt"), pki.ca.pem()).unwrap();
+        fs::write(dir.join("server.crt"), cert.pem()).unwrap();
+        fs::write(dir.join("server.key"), key.serialize_pem()).unwrap();
+        fs::write(
+            dir.join("web.yml"),
+            web_config.replace("$DIR", dir.to_str().unwrap()),
+        )
+        .unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut command = common::command();
+
+        command
+            .arg("--web.listen-address")
+            .arg(format!("127.0.0.1:{}", port))
+            .arg("--web.config.file")
+            .arg(dir.join("web.yml"))
+            .arg("--cgroup.root")
+            .arg(dir.join("cgroup"))
+            .env("TMPDIR", dir.join("tmp"));
+
+        let process = common::Process::spawn(command, dir, || {
+            TcpStream::connect(("127.0.0.1", port)).is_ok()
+        });
+
+        Exporter { process, port }
+    }
+
+    fn get(
+        &self,
+        pki: &Pki,
+        client: Option<(rcgen::Certificate, rcgen::KeyPair)>,
+        authorization: Option<&str>,
+    ) -> std::io::Result<String> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(pki.ca.der().clone()).unwrap();
+
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+
+        let config = match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    rustls::pki_types::PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let conn = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap())
+            .unwrap();
+
+        let sock = TcpStream::connect(("127.0.0.1", self.port))?;
+        let mut stream = rustls::StreamOwned::new(conn, sock);
+
+        let mut request =
+            "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n".to_string();
+
+        if let Some(authorization) = authorization {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        Ok(response)
+    }
+}
+
+fn status(response: &str) -> &str {
+    response.lines().next().unwrap_or_default()
+}
+
+#[test]
+fn tls() {
+    let pki = Pki::new();
+
+    let exporter = Exporter::start(
+        "tls",
+        &pki,
+        "tls_server_config:\n  cert_file: $DIR/server.crt\n  key_file: $DIR/server.key\n",
+    );
+
+    let response = exporter.get(&pki, None, None).unwrap();
+
+    assert_eq!(status(&response), "HTTP/1.1 200 OK");
+    assert!(response.ends_with("OK"));
+}
+
+#[test]
+fn tls_backend_is_private() {
+    let pki = Pki::new();
+
+    let exporter = Exporter::start(
+        "tls_backend",
+        &pki,
+        "tls_server_config:\n  cert_file: $DIR/server.crt\n  key_file: $DIR/server.key\n  \
+         client_auth_type: RequireAndVerifyClientCert\n  client_ca_file: $DIR/ca.crt\n",
+    );
+
+    let pid = exporter.process.child.id();
+
+    // Abstract sockets are reachable by every process in the namespace
+    let abstract_name = SocketAddr::from_abstract_name(format!("psi_exporter-{}-0", pid)).unwrap();
+    assert!(UnixStream::connect_addr(&abstract_name).is_err());
+
+    let backend = exporter
+        .process
+        .dir
+        .join(format!("tmp/psi_exporter-{}-0", pid));
+    let mode = fs::metadata(&backend).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    let socket = backend.join("backend.sock");
+    assert!(socket.exists());
+
+    // Root can get in anyway, unless it acts as another user
+    let other_user = thread::spawn(move || {
+        unsafe {
+            libc::setfsuid(65534);
+        }
+
+        UnixStream::connect(socket).map(|_| ())
+    });
+
+    if unsafe { libc::geteuid() } == 0 {
+        let err = other_user.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    // Client certificate is still required
+    assert!(exporter.get(&pki, None, None).is_err());
+}
+
+#[test]
+fn tls_client_auth() {
+    let pki = Pki::new();
+
+    let exporter = Exporter::start(
+        "mtls",
+        &pki,
+        "tls_server_config:\n  cert_file: $DIR/server.crt\n  key_file: $DIR/server.key\n  \
+         client_auth_type: RequireAndVerifyClientCert\n  client_ca_file: $DIR/ca.crt\n",
+    );
+
+    assert!(exporter.get(&pki, None, None).is_err());
+
+    let untrusted = Pki::new().issue("client");
+    assert!(exporter.get(&pki, Some(untrusted), None).is_err());
+
+    let response = exporter.get(&pki, Some(pki.issue("client")), None).unwrap();
+    assert_eq!(status(&response), "HTTP/1.1 200 OK");
+}
+
+#[test]
+fn basic_auth() {
+    let pki = Pki::new();
+
+    let hash = bcrypt::hash("hunter2", 4).unwrap();
+
+    let exporter = Exporter::start(
+        "basic_auth",
+        &pki,
+        &format!(
+            "tls_server_config:\n  cert_file: $DIR/server.crt\n  key_file: $DIR/server.key\n\
+             basic_auth_users:\n  alice: '{}'\n",
+            hash
+        ),
+    );
+
+    let response = exporter.get(&pki, None, None).unwrap();
+    assert_eq!(status(&response), "HTTP/1.1 401 Unauthorized");
+
+    // alice:wrong
+    let response = exporter
+        .get(&pki, None, Some("Basic YWxpY2U6d3Jvbmc="))
+        .unwrap();
+    assert_eq!(status(&response), "HTTP/1.1 401 Unauthorized");
+
+    // alice:hunter2
+    let response = exporter
+        .get(&pki, None, Some("Basic YWxpY2U6aHVudGVyMg=="))
+        .unwrap();
+    assert_eq!(status(&response), "HTTP/1.1 200 OK");
+}
+
+#[test]
+fn invalid_config() {
+    let output = Command::new(env!("CARGO_BIN_EXE_psi_exporter"))
+        .arg("--web.config.file")
+        .arg(Path::new("/nonexistent/web.yml"))
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+}